@@ -0,0 +1,207 @@
+use crate::message::AuthToken;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// how far a request's timestamp may be from our clock, in seconds. Nonces are
+// remembered for as long, so a captured request can't be replayed.
+const MAX_SKEW: u64 = 300;
+
+// signs our registration and checks the api server's requests with the shared secret.
+pub struct Authenticator {
+    secret: Vec<u8>,
+    node: Uuid,
+    verify_requests: bool,
+    seen_nonces: Mutex<HashMap<String, u64>>,
+}
+
+impl Authenticator {
+    pub fn new(secret: &str, node: Uuid, verify_requests: bool) -> Self {
+        Authenticator {
+            secret: secret.as_bytes().to_vec(),
+            node,
+            verify_requests,
+            seen_nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // hmac-sha256 over "<node>:<timestamp>:<nonce>", hex encoded.
+    pub fn registration_token(&self) -> AuthToken {
+        let timestamp = now();
+        let nonce = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+        let signature = self.sign(&format!("{}:{}:{}", self.node, timestamp, nonce));
+        AuthToken {
+            node: self.node,
+            timestamp,
+            nonce,
+            signature,
+        }
+    }
+
+    // a request is signed over "<node>:<timestamp>:<nonce>:<requestId>:<type>:<digest>"
+    // with our node id, so it can't be redirected to another node, reused for another
+    // request or have its parameters changed.
+    pub fn verify_request(
+        &self,
+        token: Option<&AuthToken>,
+        request_id: Option<&str>,
+        request: &str,
+        digest: &str,
+    ) -> Result<(), String> {
+        if !self.verify_requests {
+            return Ok(());
+        }
+        let token = token.ok_or("request is not signed")?;
+        if token.node != self.node {
+            return Err(format!("request is signed for node {}", token.node));
+        }
+        let now = now();
+        if now.abs_diff(token.timestamp) > MAX_SKEW {
+            return Err("request timestamp is too far from our clock".to_owned());
+        }
+        let mut mac = self.mac();
+        mac.update(
+            format!(
+                "{}:{}:{}:{}:{}:{}",
+                token.node,
+                token.timestamp,
+                token.nonce,
+                request_id.unwrap_or_default(),
+                request,
+                digest
+            )
+            .as_bytes(),
+        );
+        let signature = hex::decode(&token.signature).map_err(|_| "signature is not hex")?;
+        mac.verify_slice(&signature)
+            .map_err(|_| "signature does not match")?;
+
+        let mut seen_nonces = self.seen_nonces.lock().unwrap();
+        seen_nonces.retain(|_, timestamp| now.abs_diff(*timestamp) <= MAX_SKEW);
+        if seen_nonces
+            .insert(token.nonce.clone(), token.timestamp)
+            .is_some()
+        {
+            return Err("nonce was already used".to_owned());
+        }
+        Ok(())
+    }
+
+    fn sign(&self, data: &str) -> String {
+        let mut mac = self.mac();
+        mac.update(data.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.secret).expect("hmac takes keys of any size")
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "3b0c";
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new("secret", Uuid::new_v4(), true)
+    }
+
+    // a token as the api server would sign it for `node`.
+    fn token(auth: &Authenticator, node: Uuid, timestamp: u64, nonce: &str) -> AuthToken {
+        let signature = auth.sign(&format!(
+            "{}:{}:{}:7:getFrameAudioMixer:{}",
+            node, timestamp, nonce, DIGEST
+        ));
+        AuthToken {
+            node,
+            timestamp,
+            nonce: nonce.to_owned(),
+            signature,
+        }
+    }
+
+    fn verify(auth: &Authenticator, token: &AuthToken, digest: &str) -> Result<(), String> {
+        auth.verify_request(Some(token), Some("7"), "getFrameAudioMixer", digest)
+    }
+
+    #[test]
+    fn accepts_a_signed_request() {
+        let auth = authenticator();
+        let token = token(&auth, auth.node, now(), "a");
+        assert_eq!(verify(&auth, &token, DIGEST), Ok(()));
+    }
+
+    #[test]
+    fn refuses_unsigned_requests_only_when_verifying() {
+        let auth = authenticator();
+        assert!(auth
+            .verify_request(None, Some("7"), "getFrameAudioMixer", DIGEST)
+            .is_err());
+        let auth = Authenticator::new("secret", Uuid::new_v4(), false);
+        assert_eq!(
+            auth.verify_request(None, Some("7"), "getFrameAudioMixer", DIGEST),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn refuses_a_request_for_another_node() {
+        let auth = authenticator();
+        let token = token(&auth, Uuid::new_v4(), now(), "a");
+        assert!(verify(&auth, &token, DIGEST).is_err());
+    }
+
+    #[test]
+    fn refuses_timestamps_outside_the_skew() {
+        let auth = authenticator();
+        let late = token(&auth, auth.node, now() - MAX_SKEW - 10, "a");
+        assert!(verify(&auth, &late, DIGEST).is_err());
+        let early = token(&auth, auth.node, now() + MAX_SKEW + 10, "b");
+        assert!(verify(&auth, &early, DIGEST).is_err());
+    }
+
+    #[test]
+    fn refuses_a_replayed_nonce() {
+        let auth = authenticator();
+        let token = token(&auth, auth.node, now(), "a");
+        assert_eq!(verify(&auth, &token, DIGEST), Ok(()));
+        assert!(verify(&auth, &token, DIGEST).is_err());
+    }
+
+    #[test]
+    fn refuses_a_changed_message() {
+        let auth = authenticator();
+        let token = token(&auth, auth.node, now(), "a");
+        assert!(verify(&auth, &token, "3b0d").is_err());
+        // the failed attempt didn't use up the nonce.
+        assert_eq!(verify(&auth, &token, DIGEST), Ok(()));
+    }
+
+    #[test]
+    fn refuses_a_signature_that_is_not_hex() {
+        let auth = authenticator();
+        let mut token = token(&auth, auth.node, now(), "a");
+        token.signature.replace_range(..2, "zz");
+        assert_eq!(
+            verify(&auth, &token, DIGEST),
+            Err("signature is not hex".to_owned())
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use rand::Rng;
+use std::time::Duration;
+
+// delays between reconnect attempts: doubling from `initial` up to `max`, each one
+// randomized into its upper half so restarted nodes don't reconnect in lockstep.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    max_attempts: Option<u32>,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, max_attempts: Option<u32>) -> Self {
+        Backoff {
+            initial,
+            max,
+            max_attempts,
+            attempt: 0,
+        }
+    }
+
+    // delay before the next attempt, or None once the attempts are used up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.max_attempts.map(|max| self.attempt >= max) == Some(true) {
+            return None;
+        }
+        let delay = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt += 1;
+        Some(delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0)))
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+
+    // called once a connection is up, so the next outage starts from `initial` again.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_between(delay: Option<Duration>, full: Duration) {
+        let delay = delay.expect("attempts left");
+        assert!(
+            delay >= full / 2 && delay <= full,
+            "{:?} not within half of {:?}",
+            delay,
+            full
+        );
+    }
+
+    #[test]
+    fn doubles_up_to_max() {
+        let second = Duration::from_secs(1);
+        let mut backoff = Backoff::new(second, second * 5, None);
+        assert_between(backoff.next_delay(), second);
+        assert_between(backoff.next_delay(), second * 2);
+        assert_between(backoff.next_delay(), second * 4);
+        assert_between(backoff.next_delay(), second * 5);
+        assert_between(backoff.next_delay(), second * 5);
+        assert_eq!(backoff.attempts(), 5);
+    }
+
+    #[test]
+    fn does_not_overflow() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60), None);
+        for _ in 0..100 {
+            backoff.next_delay();
+        }
+        assert_between(backoff.next_delay(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut backoff = Backoff::new(Duration::from_millis(1), Duration::from_secs(1), Some(2));
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.attempts(), 2);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let second = Duration::from_secs(1);
+        let mut backoff = Backoff::new(second, second * 60, Some(3));
+        for _ in 0..3 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), None);
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_between(backoff.next_delay(), second);
+    }
+}
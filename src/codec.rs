@@ -1,103 +1,310 @@
-use std::io;
+use std::{fmt, io, marker::PhantomData};
 
-use byteorder::{BigEndian, ByteOrder};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
 
-use super::message::{RequestMessage, ResponseMessage};
-
-pub struct Client;
+// how the length in front of each json message is written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Framing {
+    // 2-byte big-endian length of the message alone.
+    #[allow(dead_code)]
+    Client,
+    // 4-byte big-endian length of the message plus the 4 bytes of the length itself.
+    Server,
+}
 
-impl Decoder for Client {
-    type Item = RequestMessage;
-    type Error = io::Error;
+impl Framing {
+    fn header_len(self) -> usize {
+        match self {
+            Framing::Client => 2,
+            Framing::Server => 4,
+        }
+    }
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
-            }
-            BigEndian::read_u16(src.as_ref()) as usize
-        };
-
-        if src.len() >= size + 2 {
-            let _ = src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(serde_json::from_slice::<RequestMessage>(&buf)?))
-        } else {
-            Ok(None)
+    // largest message the header can describe.
+    fn max_len(self) -> usize {
+        match self {
+            Framing::Client => u16::MAX as usize,
+            Framing::Server => u32::MAX as usize - 4,
         }
     }
 }
 
-impl Encoder<ResponseMessage> for Client {
-    type Error = io::Error;
-    fn encode(&mut self, msg: ResponseMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = serde_json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
-
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16(msg_ref.len() as u16);
-        dst.put(msg_ref);
+// a message that couldn't be decoded. The frame was consumed, so the next one
+// still can be; the connection only fails on errors it can't resync from.
+#[derive(Debug)]
+pub enum FrameError {
+    TooLarge {
+        size: usize,
+        max: usize,
+    },
+    Json {
+        error: serde_json::Error,
+        frame: Bytes,
+    },
+}
 
-        Ok(())
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { size, max } => {
+                write!(f, "message of {} bytes exceeds the limit of {}", size, max)
+            }
+            FrameError::Json { error, .. } => write!(f, "malformed message: {}", error),
+        }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct Server {
+impl std::error::Error for FrameError {}
+
+// json messages behind a length prefix, decoded as `T`; what's encoded is up to the caller.
+#[derive(Debug)]
+pub struct Codec<T> {
+    framing: Framing,
+    max_frame_size: usize,
+    // length of the message whose header was already read.
     size: Option<usize>,
+    // bytes left of an oversized message being dropped.
+    discard: usize,
+    _decodes: PhantomData<fn() -> T>,
 }
 
-impl Default for Server {
-    fn default() -> Self {
-        Self { size: None }
+impl<T> Codec<T> {
+    pub fn new(framing: Framing, max_frame_size: usize) -> Self {
+        Codec {
+            framing,
+            max_frame_size: max_frame_size.min(framing.max_len()),
+            size: None,
+            discard: 0,
+            _decodes: PhantomData,
+        }
     }
 }
 
-impl Decoder for Server {
-    type Item = RequestMessage;
+impl<T: DeserializeOwned> Decoder for Codec<T> {
+    type Item = Result<T, FrameError>;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         loop {
+            if self.discard > 0 {
+                let dropped = self.discard.min(src.len());
+                src.advance(dropped);
+                self.discard -= dropped;
+                if self.discard > 0 {
+                    return Ok(None);
+                }
+            }
             match self.size {
                 Some(size) => {
-                    if src.len() >= size {
-                        let msg = serde_json::from_slice(&src[..size])?;
-                        src.advance(size);
-                        self.size = None;
-                        return Ok(Some(msg));
-                    } else {
+                    if src.len() < size {
+                        src.reserve(size - src.len());
                         return Ok(None);
                     }
+                    self.size = None;
+                    let frame = src.split_to(size).freeze();
+                    let msg = serde_json::from_slice(&frame)
+                        .map_err(|error| FrameError::Json { error, frame });
+                    return Ok(Some(msg));
                 }
                 None => {
-                    if src.len() < 4 {
+                    let header_len = self.framing.header_len();
+                    if src.len() < header_len {
                         return Ok(None);
                     }
-                    self.size = Some(BigEndian::read_u32(&src[..4]) as usize - 4);
-                    src.advance(4);
+                    let size = match self.framing {
+                        Framing::Client => src.get_u16() as usize,
+                        Framing::Server => {
+                            let len = src.get_u32() as usize;
+                            // a length short of its own header leaves nothing to resync on.
+                            len.checked_sub(header_len).ok_or_else(|| {
+                                io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    format!("frame length {} is shorter than its header", len),
+                                )
+                            })?
+                        }
+                    };
+                    if size > self.max_frame_size {
+                        self.discard = size;
+                        return Ok(Some(Err(FrameError::TooLarge {
+                            size,
+                            max: self.max_frame_size,
+                        })));
+                    }
+                    self.size = Some(size);
                 }
             }
         }
     }
 }
 
-impl Encoder<ResponseMessage> for Server {
+impl<T, M: Serialize> Encoder<M> for Codec<T> {
     type Error = io::Error;
 
-    fn encode(&mut self, msg: ResponseMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let base = dst.len();
+    fn encode(&mut self, msg: M, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let msg = serde_json::to_vec(&msg)?;
+        if msg.len() > self.max_frame_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                FrameError::TooLarge {
+                    size: msg.len(),
+                    max: self.max_frame_size,
+                },
+            ));
+        }
 
-        dst.extend_from_slice(&[0; 4]);
+        dst.reserve(msg.len() + self.framing.header_len());
+        match self.framing {
+            Framing::Client => dst.put_u16(msg.len() as u16),
+            Framing::Server => dst.put_u32((msg.len() + 4) as u32),
+        }
+        dst.put_slice(&msg);
 
-        serde_json::to_writer(dst.writer(), &msg).unwrap();
+        Ok(())
+    }
+}
 
-        let len = (dst.len() - base) as u32;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{MessageRequest, RequestMessage};
+    use sha2::{Digest, Sha256};
 
-        (&mut dst[base..]).put_u32(len);
+    fn request(session_id: &str) -> RequestMessage {
+        RequestMessage::IncomingServer {
+            node: None,
+            wsid: Some("ws-1".to_owned()),
+            request_id: Some("7".to_owned()),
+            auth: None,
+            message: MessageRequest::getFrameAudioMixer {
+                session_id: session_id.to_owned(),
+            },
+            digest: String::new(),
+        }
+    }
 
-        Ok(())
+    fn session_id(msg: Option<Result<RequestMessage, FrameError>>) -> String {
+        match msg {
+            Some(Ok(RequestMessage::IncomingServer { message, .. })) => {
+                message.session_id().unwrap().to_owned()
+            }
+            other => panic!("expected a request, got {:?}", other),
+        }
+    }
+
+    fn round_trip(framing: Framing) {
+        let mut codec = Codec::<RequestMessage>::new(framing, 1024);
+        let mut buf = BytesMut::new();
+        codec.encode(request("a"), &mut buf).unwrap();
+        codec.encode(request("b"), &mut buf).unwrap();
+
+        // fed a byte at a time, nothing comes out until a message is complete.
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in buf {
+            src.put_u8(byte);
+            if let Some(msg) = codec.decode(&mut src).unwrap() {
+                decoded.push(session_id(Some(msg)));
+            }
+        }
+        assert_eq!(decoded, ["a", "b"]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn round_trips_client_framing() {
+        round_trip(Framing::Client);
+    }
+
+    #[test]
+    fn round_trips_server_framing() {
+        round_trip(Framing::Server);
+    }
+
+    #[test]
+    fn server_length_counts_itself() {
+        let mut codec = Codec::<RequestMessage>::new(Framing::Server, 1024);
+        let mut buf = BytesMut::new();
+        codec.encode(request("a"), &mut buf).unwrap();
+        assert_eq!((&buf[..]).get_u32() as usize, buf.len());
+    }
+
+    #[test]
+    fn rejects_server_length_shorter_than_header() {
+        let mut codec = Codec::<RequestMessage>::new(Framing::Server, 1024);
+        let mut src = BytesMut::from(&[0u8, 0, 0, 3][..]);
+        let err = codec.decode(&mut src).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn skips_malformed_json() {
+        let mut codec = Codec::<RequestMessage>::new(Framing::Server, 1024);
+        let mut src = BytesMut::new();
+        src.put_u32(4 + 5);
+        src.put_slice(b"{nope");
+        codec.encode(request("a"), &mut src).unwrap();
+
+        match codec.decode(&mut src).unwrap() {
+            Some(Err(FrameError::Json { frame, .. })) => assert_eq!(&frame[..], b"{nope"),
+            other => panic!("expected a json error, got {:?}", other),
+        }
+        assert_eq!(session_id(codec.decode(&mut src).unwrap()), "a");
+    }
+
+    #[test]
+    fn skips_oversized_frames() {
+        let mut codec = Codec::<RequestMessage>::new(Framing::Client, 16);
+        let mut src = BytesMut::new();
+        src.put_u16(100);
+        src.put_slice(&[b'x'; 60]);
+
+        match codec.decode(&mut src).unwrap() {
+            Some(Err(FrameError::TooLarge { size: 100, max: 16 })) => {}
+            other => panic!("expected too large, got {:?}", other),
+        }
+        // the rest of the oversized frame arrives later and is dropped as well.
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.put_slice(&[b'x'; 40]);
+        src.put_u16(2);
+        src.put_slice(b"{}");
+        match codec.decode(&mut src).unwrap() {
+            Some(Err(FrameError::Json { frame, .. })) => assert_eq!(&frame[..], b"{}"),
+            other => panic!("expected the next frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_to_encode_oversized_messages() {
+        let mut codec = Codec::<RequestMessage>::new(Framing::Server, 16);
+        let err = codec
+            .encode(request("a"), &mut BytesMut::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn digests_message_regardless_of_layout() {
+        let mut codec = Codec::<RequestMessage>::new(Framing::Client, 1024);
+        let mut src = BytesMut::new();
+        for frame in [
+            &br#"{"message":{"type":"getFrameAudioMixer","sessionId":"a"}}"#[..],
+            &br#"{ "message": { "sessionId": "a", "type": "getFrameAudioMixer" } }"#[..],
+        ] {
+            src.put_u16(frame.len() as u16);
+            src.put_slice(frame);
+        }
+        let canonical = r#"{"sessionId":"a","type":"getFrameAudioMixer"}"#;
+        let expected = hex::encode(Sha256::digest(canonical));
+        for _ in 0..2 {
+            match codec.decode(&mut src).unwrap() {
+                Some(Ok(RequestMessage::IncomingServer { digest, .. })) => {
+                    assert_eq!(digest, expected)
+                }
+                other => panic!("expected a request, got {:?}", other),
+            }
+        }
     }
 }
@@ -1,15 +1,478 @@
+use crate::message::CodecPreferences;
+use crate::transport::{Endpoint, Scheme};
+use clap::Parser;
+use derive_more::Display;
+use serde::Deserialize;
+use std::{
+    fs,
+    net::{IpAddr, Ipv6Addr},
+    path::Path,
+    time::Duration,
+};
 use uuid::Uuid;
 
+// Every setting can be given on the command line, through the environment or in
+// the config file. Command line wins over environment, which wins over the file.
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    /// api server to register with, [tcp://|ws://|wss://]host[:port][/path]
+    #[clap(short, long, env = "signalingserver")]
+    url: Option<String>,
+    /// connect to the api server over tls
+    #[clap(long, env = "GMIX_TLS", num_args = 0..=1, default_missing_value = "true")]
+    tls: Option<bool>,
+    /// pem bundle of CAs trusted for the api server, the webpki roots if unset
+    #[clap(long, env = "GMIX_TLS_CA")]
+    tls_ca: Option<String>,
+    /// pem client certificate chain presented to the api server
+    #[clap(long, env = "GMIX_TLS_CERT")]
+    tls_cert: Option<String>,
+    /// pem private key of the client certificate
+    #[clap(long, env = "GMIX_TLS_KEY")]
+    tls_key: Option<String>,
+    /// name the api server certificate is checked against, the url host if unset
+    #[clap(long, env = "GMIX_TLS_SERVER_NAME")]
+    tls_server_name: Option<String>,
+    /// shared secret for signing registration and verifying requests
+    #[clap(long, env = "GMIX_AUTH_SECRET", hide_env_values = true)]
+    auth_secret: Option<String>,
+    /// reject requests from the api server that aren't signed with auth_secret
+    #[clap(
+        long,
+        env = "GMIX_VERIFY_REQUESTS",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    verify_requests: Option<bool>,
+    /// toml or json file with any of the settings below
+    #[clap(short, long, env = "GMIX_CONFIG")]
+    config: Option<String>,
+    #[clap(long, env = "GMIX_REGION")]
+    region: Option<String>,
+    /// keep the same node id across restarts so the api server recognizes this node
+    #[clap(long, env = "GMIX_NODE_ID")]
+    node_id: Option<Uuid>,
+    /// udp ports handed out to mixer inputs, e.g. 5000-5100
+    #[clap(long, env = "GMIX_PORT_RANGE")]
+    port_range: Option<String>,
+    /// address mediasoup should send rtp to
+    #[clap(long, env = "GMIX_PUBLIC_IP")]
+    public_ip: Option<IpAddr>,
+    /// seconds between serverLoad heartbeats
+    #[clap(long, env = "GMIX_HEARTBEAT_INTERVAL")]
+    heartbeat_interval: Option<u64>,
+    /// seconds without any message from the api server before the connection is dropped
+    #[clap(long, env = "GMIX_KEEPALIVE_TIMEOUT")]
+    keepalive_timeout: Option<u64>,
+    /// largest message in bytes accepted from or sent to the api server
+    #[clap(long, env = "GMIX_MAX_FRAME_SIZE")]
+    max_frame_size: Option<usize>,
+    /// seconds to wait before the first reconnect to the api server, doubled on every failure
+    #[clap(long, env = "GMIX_RECONNECT_DELAY")]
+    reconnect_delay: Option<u64>,
+    /// upper bound in seconds for the reconnect delay
+    #[clap(long, env = "GMIX_RECONNECT_MAX_DELAY")]
+    reconnect_max_delay: Option<u64>,
+    /// give up and exit after this many failed reconnects in a row, retry forever if unset
+    #[clap(long, env = "GMIX_RECONNECT_MAX_ATTEMPTS")]
+    reconnect_max_attempts: Option<u32>,
+    /// seconds running sessions get to end after SIGTERM/SIGINT before they are stopped
+    #[clap(long, env = "GMIX_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: Option<u64>,
+    /// rtp payload type used when a request doesn't name one
+    #[clap(long, env = "GMIX_PAYLOAD_TYPE")]
+    payload_type: Option<u8>,
+    #[clap(long, env = "GMIX_CLOCK_RATE")]
+    clock_rate: Option<i32>,
+    /// opus bitrate of the mixed output
+    #[clap(long, env = "GMIX_BITRATE")]
+    bitrate: Option<i32>,
+    /// directory recordings are written to
+    #[clap(long, env = "GMIX_RECORDING_DIR")]
+    recording_dir: Option<String>,
+    /// seconds after which a recording moves on to a new file, unless the request says otherwise
+    #[clap(long, env = "GMIX_RECORDING_MAX_DURATION")]
+    recording_max_duration: Option<u64>,
+    /// bytes after which a recording moves on to a new file, unless the request says otherwise
+    #[clap(long, env = "GMIX_RECORDING_MAX_SIZE")]
+    recording_max_size: Option<u64>,
+    /// tracing filter, e.g. gmix=debug
+    #[clap(long, env = "RUST_LOG")]
+    log: Option<String>,
+}
+
+// the same settings as `Args`, as read from the config file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    url: Option<String>,
+    tls: Option<bool>,
+    tls_ca: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    tls_server_name: Option<String>,
+    auth_secret: Option<String>,
+    verify_requests: Option<bool>,
+    region: Option<String>,
+    node_id: Option<Uuid>,
+    port_range: Option<String>,
+    public_ip: Option<IpAddr>,
+    heartbeat_interval: Option<u64>,
+    keepalive_timeout: Option<u64>,
+    max_frame_size: Option<usize>,
+    reconnect_delay: Option<u64>,
+    reconnect_max_delay: Option<u64>,
+    reconnect_max_attempts: Option<u32>,
+    shutdown_timeout: Option<u64>,
+    payload_type: Option<u8>,
+    clock_rate: Option<i32>,
+    bitrate: Option<i32>,
+    recording_dir: Option<String>,
+    recording_max_duration: Option<u64>,
+    recording_max_size: Option<u64>,
+    log: Option<String>,
+}
+
+#[derive(Debug, Display)]
+pub enum ConfigError {
+    #[display(fmt = "can not read {}: {}", _0, _1)]
+    Read(String, std::io::Error),
+    #[display(fmt = "can not parse config file {}: {}", _0, _1)]
+    Parse(String, String),
+    #[display(fmt = "missing required setting {}", _0)]
+    Missing(&'static str),
+    #[display(fmt = "invalid {}: {}", _0, _1)]
+    Invalid(&'static str, String),
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub ca: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub server_name: Option<String>,
+}
+
+// where recordings go and when they are split into files by default.
+#[derive(Clone, Debug)]
+pub struct RecordingConfig {
+    pub dir: String,
+    pub max_duration: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
+    pub url: String,
+    pub endpoint: Endpoint,
+    pub tls: TlsConfig,
+    pub auth_secret: Option<String>,
+    pub verify_requests: bool,
     pub mode: String,
+    pub region: String,
     pub node: Uuid,
+    pub port_range: (u16, u16),
+    pub public_ip: Option<IpAddr>,
+    pub heartbeat_interval: Duration,
+    pub keepalive_timeout: Duration,
+    pub max_frame_size: usize,
+    pub reconnect_delay: Duration,
+    pub reconnect_max_delay: Duration,
+    pub reconnect_max_attempts: Option<u32>,
+    pub shutdown_timeout: Duration,
+    pub codec: CodecPreferences,
+    pub recording: RecordingConfig,
+    pub log: String,
 }
 
 impl Config {
-    pub fn init() -> Config {
-        let mode = "audioMixer".to_owned();
-        let node = Uuid::new_v4();
-        Config { mode, node }
+    pub fn load(args: Args) -> Result<Config, ConfigError> {
+        let file = match &args.config {
+            Some(path) => read_file(path)?,
+            None => FileConfig::default(),
+        };
+        let default_codec = CodecPreferences::default();
+
+        let url = args.url.or(file.url).ok_or(ConfigError::Missing("url"))?;
+        let endpoint = parse_url(&url)?;
+        let port_range = match args.port_range.or(file.port_range) {
+            Some(port_range) => parse_port_range(&port_range)?,
+            None => (5000, 5100),
+        };
+        let heartbeat_interval = args
+            .heartbeat_interval
+            .or(file.heartbeat_interval)
+            .unwrap_or(10);
+        if heartbeat_interval == 0 {
+            return Err(ConfigError::Invalid(
+                "heartbeat_interval",
+                "must be at least 1 second".to_owned(),
+            ));
+        }
+        let keepalive_timeout = args
+            .keepalive_timeout
+            .or(file.keepalive_timeout)
+            .unwrap_or(30);
+        // the api server gets at least one ping before it is given up on.
+        if keepalive_timeout <= heartbeat_interval {
+            return Err(ConfigError::Invalid(
+                "keepalive_timeout",
+                "must be longer than heartbeat_interval".to_owned(),
+            ));
+        }
+        let max_frame_size = args
+            .max_frame_size
+            .or(file.max_frame_size)
+            .unwrap_or(1024 * 1024);
+        // registration with all its sessions has to fit.
+        if max_frame_size < 4096 {
+            return Err(ConfigError::Invalid(
+                "max_frame_size",
+                "must be at least 4096 bytes".to_owned(),
+            ));
+        }
+        let reconnect_delay = args.reconnect_delay.or(file.reconnect_delay).unwrap_or(2);
+        let reconnect_max_delay = args
+            .reconnect_max_delay
+            .or(file.reconnect_max_delay)
+            .unwrap_or(60);
+        if reconnect_max_delay < reconnect_delay {
+            return Err(ConfigError::Invalid(
+                "reconnect_max_delay",
+                "must not be less than reconnect_delay".to_owned(),
+            ));
+        }
+        let auth_secret = args.auth_secret.or(file.auth_secret);
+        let verify_requests = args
+            .verify_requests
+            .or(file.verify_requests)
+            .unwrap_or(false);
+        if verify_requests && auth_secret.is_none() {
+            return Err(ConfigError::Missing("auth_secret"));
+        }
+        if auth_secret.as_deref() == Some("") {
+            return Err(ConfigError::Invalid(
+                "auth_secret",
+                "must not be empty".to_owned(),
+            ));
+        }
+        let codec = CodecPreferences {
+            payload_type: args
+                .payload_type
+                .or(file.payload_type)
+                .unwrap_or(default_codec.payload_type),
+            clock_rate: args
+                .clock_rate
+                .or(file.clock_rate)
+                .unwrap_or(default_codec.clock_rate),
+            bitrate: args
+                .bitrate
+                .or(file.bitrate)
+                .unwrap_or(default_codec.bitrate),
+        };
+        codec
+            .validate()
+            .map_err(|e| ConfigError::Invalid("codec", e))?;
+
+        Ok(Config {
+            url,
+            endpoint,
+            tls: TlsConfig {
+                enabled: args.tls.or(file.tls).unwrap_or(false),
+                ca: args.tls_ca.or(file.tls_ca),
+                cert: args.tls_cert.or(file.tls_cert),
+                key: args.tls_key.or(file.tls_key),
+                server_name: args.tls_server_name.or(file.tls_server_name),
+            },
+            auth_secret,
+            verify_requests,
+            mode: "audioMixer".to_owned(),
+            region: args
+                .region
+                .or(file.region)
+                .unwrap_or_else(|| "local".to_owned()),
+            node: args.node_id.or(file.node_id).unwrap_or_else(Uuid::new_v4),
+            port_range,
+            public_ip: args.public_ip.or(file.public_ip),
+            heartbeat_interval: Duration::from_secs(heartbeat_interval),
+            keepalive_timeout: Duration::from_secs(keepalive_timeout),
+            max_frame_size,
+            reconnect_delay: Duration::from_secs(reconnect_delay),
+            reconnect_max_delay: Duration::from_secs(reconnect_max_delay),
+            reconnect_max_attempts: args.reconnect_max_attempts.or(file.reconnect_max_attempts),
+            shutdown_timeout: Duration::from_secs(
+                args.shutdown_timeout
+                    .or(file.shutdown_timeout)
+                    .unwrap_or(10),
+            ),
+            codec,
+            recording: RecordingConfig {
+                dir: args
+                    .recording_dir
+                    .or(file.recording_dir)
+                    .unwrap_or_else(|| "recordings".to_owned()),
+                max_duration: args.recording_max_duration.or(file.recording_max_duration),
+                max_size: args.recording_max_size.or(file.recording_max_size),
+            },
+            log: args
+                .log
+                .or(file.log)
+                .unwrap_or_else(|| "gmix=info".to_owned()),
+        })
+    }
+}
+
+fn read_file(path: &str) -> Result<FileConfig, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+    let parsed = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str(&content).map_err(|e| e.to_string()),
+        _ => toml::from_str(&content).map_err(|e| e.to_string()),
+    };
+    parsed.map_err(|e| ConfigError::Parse(path.to_owned(), e))
+}
+
+// [scheme://]host[:port][/path], where host may be a bracketed or, without port,
+// bare ipv6 address. Without a scheme the url is tcp.
+fn parse_url(url: &str) -> Result<Endpoint, ConfigError> {
+    let invalid = |reason: &str| ConfigError::Invalid("url", format!("{:?} {}", url, reason));
+    let (scheme, rest) = match url.split_once("://") {
+        Some(("tcp", rest)) => (Scheme::Tcp, rest),
+        Some(("ws", rest)) => (Scheme::Ws, rest),
+        Some(("wss", rest)) => (Scheme::Wss, rest),
+        Some(_) => return Err(invalid("must be tcp://, ws:// or wss://")),
+        None => (Scheme::Tcp, url),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if scheme == Scheme::Tcp && path != "/" {
+        return Err(invalid("has a path, which only ws urls can have"));
+    }
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| invalid("has a bad port"));
+    let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+        let (host, port) = bracketed
+            .split_once(']')
+            .ok_or_else(|| invalid("has an unclosed ["))?;
+        match port.strip_prefix(':') {
+            Some(port) => (host, Some(parse_port(port)?)),
+            None if port.is_empty() => (host, None),
+            None => return Err(invalid("has junk after ]")),
+        }
+    } else if authority.parse::<Ipv6Addr>().is_ok() {
+        (authority, None)
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(parse_port(port)?)),
+            None => (authority, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(invalid("has no host"));
+    }
+    Ok(Endpoint {
+        scheme,
+        host: host.to_owned(),
+        port: port.unwrap_or_else(|| scheme.default_port()),
+        path: path.to_owned(),
+    })
+}
+
+// "5000-5100"
+fn parse_port_range(port_range: &str) -> Result<(u16, u16), ConfigError> {
+    let invalid = || {
+        ConfigError::Invalid(
+            "port_range",
+            format!("expected <start>-<end>, got {:?}", port_range),
+        )
+    };
+    let (start, end) = port_range.split_once('-').ok_or_else(invalid)?;
+    let start = start.trim().parse::<u16>().map_err(|_| invalid())?;
+    let end = end.trim().parse::<u16>().map_err(|_| invalid())?;
+    if start == 0 || start > end {
+        return Err(invalid());
+    }
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(url: &str) -> (Scheme, String, u16, String) {
+        let endpoint = parse_url(url).unwrap();
+        (endpoint.scheme, endpoint.host, endpoint.port, endpoint.path)
+    }
+
+    fn tcp(host: &str, port: u16) -> (Scheme, String, u16, String) {
+        (Scheme::Tcp, host.to_owned(), port, "/".to_owned())
+    }
+
+    #[test]
+    fn parses_host_and_port() {
+        assert_eq!(endpoint("api.local:2000"), tcp("api.local", 2000));
+        assert_eq!(endpoint("tcp://10.0.0.1"), tcp("10.0.0.1", 1188));
+    }
+
+    #[test]
+    fn parses_ipv6_hosts() {
+        assert_eq!(endpoint("[::1]:2000"), tcp("::1", 2000));
+        assert_eq!(endpoint("[fe80::1]"), tcp("fe80::1", 1188));
+        // without brackets the whole authority is the address, never a port.
+        assert_eq!(endpoint("fe80::1:2000"), tcp("fe80::1:2000", 1188));
+        assert_eq!(
+            endpoint("ws://[::1]/mixer"),
+            (Scheme::Ws, "::1".to_owned(), 80, "/mixer".to_owned())
+        );
+    }
+
+    #[test]
+    fn websocket_urls_keep_their_path() {
+        assert_eq!(
+            endpoint("wss://api.example.com/gmix?node=1"),
+            (
+                Scheme::Wss,
+                "api.example.com".to_owned(),
+                443,
+                "/gmix?node=1".to_owned()
+            )
+        );
+        assert_eq!(
+            endpoint("ws://api:8080"),
+            (Scheme::Ws, "api".to_owned(), 8080, "/".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_urls() {
+        for url in [
+            "http://api:80",
+            "tcp://api:80/path",
+            "api:port",
+            "api:70000",
+            "[::1",
+            "[::1]x",
+            ":2000",
+            "ws:///path",
+        ] {
+            assert!(parse_url(url).is_err(), "{} should be rejected", url);
+        }
+    }
+
+    #[test]
+    fn parses_port_ranges() {
+        assert_eq!(parse_port_range("5000-5100").unwrap(), (5000, 5100));
+        assert_eq!(parse_port_range(" 7000 - 7000 ").unwrap(), (7000, 7000));
+        for port_range in ["5000", "5100-5000", "0-10", "a-b", "5000-70000"] {
+            assert!(
+                parse_port_range(port_range).is_err(),
+                "{} should be rejected",
+                port_range
+            );
+        }
     }
 }
@@ -1,124 +1,412 @@
-use super::codec;
 use crate::{
+    auth::Authenticator,
+    codec::FrameError,
     config::Config,
-    message::{MessageRequest, MessageResponse, RequestMessage, ResponseMessage},
-    mixer::session_manager::MixerSessionManager,
+    load::{self, CpuMonitor},
+    message::{
+        CodecCapability, ErrorCode, MessageRequest, MessageResponse, RequestMessage,
+        ResponseMessage,
+    },
+    mixer::{session_manager::MixerSessionManager, MixerError, MixerEvent},
+    transport::Connection,
 };
 use futures::{future::poll_fn, ready, sink::Sink, stream::StreamExt};
-use std::{
-    collections::VecDeque,
-    net::{IpAddr, SocketAddr, ToSocketAddrs},
-    pin::Pin,
-    str::FromStr,
-    task::Poll,
-    thread,
-    time::Duration,
-};
-use tokio::spawn;
+use serde_json::Value;
+use std::{collections::VecDeque, net::IpAddr, pin::Pin, sync::Arc, task::Poll};
 use tokio::{
-    net::TcpStream,
     pin, select,
+    sync::mpsc::UnboundedReceiver,
     time::{sleep, Instant},
 };
-use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::trace;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, trace, warn};
 use uuid::Uuid;
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
-pub async fn handle_stream(mut stream: TcpStream, config: Config) -> Result<(), Error> {
-    stream.set_nodelay(true)?;
-    let (read, write) = stream.split();
-    let mut read = FramedRead::new(read, codec::Server::default());
-    let mut write = FramedWrite::new(write, codec::Server::default());
+pub async fn handle_stream(
+    connection: Connection,
+    config: Config,
+    manager: Arc<MixerSessionManager>,
+    events: &mut UnboundedReceiver<MixerEvent>,
+    shutdown: &CancellationToken,
+    auth: Option<&Authenticator>,
+) -> Result<(), Error> {
+    // without an announced address, the one the api server already reaches us on.
+    let media_ip = match config.public_ip {
+        Some(ip) => ip,
+        None => connection.local_ip,
+    };
+    let mut read = connection.read;
+    let mut write = connection.write;
 
     let mut queue_write = QueuedWrite::new(&mut write);
     let server_id = config.clone().node;
-    let timer = sleep(Duration::from_secs(10));
+    let timer = sleep(config.heartbeat_interval);
     pin!(timer);
+    // any message from the api server proves it's alive and pushes this back.
+    let mut last_seen = Instant::now();
+    let dead = sleep(config.keepalive_timeout);
+    pin!(dead);
     let register_response = ResponseMessage::OutgoingServer {
         node: Some(config.clone().node),
+        wsid: None,
+        request_id: None,
         message: MessageResponse::registerMixingServer {
             mode: config.clone().mode,
-            region: "local".to_owned(),
+            region: config.clone().region,
+            ip: media_ip,
+            port_range: config.port_range,
+            port_capacity: manager.port_capacity().1,
+            codecs: vec![CodecCapability {
+                mime_type: "audio/opus".to_owned(),
+                clock_rate: config.codec.clock_rate,
+                channels: 2,
+            }],
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            // sessions outlive the connection, so after a reconnect they are still here.
+            sessions: manager.list_sessions(),
+            token: auth.map(Authenticator::registration_token),
         },
     };
     queue_write.push(register_response);
+    let mut cpu_monitor = CpuMonitor::new();
     loop {
         select! {
             opt = read.next() => {
                 match opt {
                     Some(res) => {
-                        let msg: RequestMessage = res?;
+                        let decoded = res?;
+                        last_seen = Instant::now();
+                        dead.as_mut().reset(last_seen + config.keepalive_timeout);
+                        let msg: RequestMessage = match decoded {
+                            Ok(msg) => msg,
+                            Err(e) => {
+                                warn!("dropping message: {}", e);
+                                queue_write.push(malformed_response(server_id, e));
+                                continue;
+                            }
+                        };
                         trace!("{msg:?}");
                         // handle request message.
                         match msg {
-                        RequestMessage::IncomingServer { node, wsid, message } => {
-                            println!("incoming message: {:?}", &message);
-                            match message {
-                            MessageRequest::createFrameAudioMixer { hello } => {
-                                let port_range_start = (5000, 5100).0;
-                                let port_range_end = (500, 5100).1;
-                                let destination_port = 6000;
-                                gstreamer::init()?;
-                                let port_range = (port_range_start, port_range_end);
-                                let mixer_manager = MixerSessionManager::new(port_range.clone());
-                                let destination_ip1 = "127.0.0.1";
-                                let session_id1 = "session1".to_string();
-                                let ports = mixer_manager.create_session(session_id1.clone(), 2, destination_ip1, destination_port).unwrap();
-                                let mixer_manager_clone = mixer_manager;
-                                println!("before spawn");
-                                println!("createFrameAudioMixer {}", &hello);
-                                let _ = thread::spawn(move || {
-                                    let rt = tokio::runtime::Runtime::new().unwrap();
-                                    rt.block_on(async move {
-                                        mixer_manager_clone.start_session(&session_id1.clone()).await.unwrap();
-                                    })
-                                });
-                                let response = ResponseMessage::OutgoingServer {
-                                    node: Some(server_id),
-                                    message: MessageResponse::createdFrameAudioMixer { port_range: ports, destination_port },
-                                };
-                                println!("before sending out message: ");
-                                queue_write.push(response);
-                                // let _ = session1_handle.join();
-                            },
-                            MessageRequest::destroyFrameAudioMixer {  } => {
-                                println!("destroyedFrameAudioMixer");
-                                let response = ResponseMessage::OutgoingServer {
-                                    node: Some(server_id),
-                                    message: MessageResponse::destroyFrameAudioMixer {},
-                                };
-                                queue_write.push(response);
-                            },
+                        RequestMessage::IncomingServer { message: MessageRequest::pong {}, .. } => {},
+                        RequestMessage::IncomingServer { wsid, request_id, auth: token, message, digest, .. } => {
+                            let request = message.name();
+                            let session_id = message.session_id().map(str::to_owned);
+                            let verified = match auth {
+                                Some(auth) => auth
+                                    .verify_request(token.as_ref(), request_id.as_deref(), request, &digest)
+                                    .map_err(|e| MixerError::new(ErrorCode::Unauthorized, e)),
+                                None => Ok(()),
+                            };
+                            let result = match verified {
+                                Ok(()) => handle_request(message, &manager, &config, media_ip).await,
+                                Err(e) => Err(e),
+                            };
+                            let message = match result {
+                                Ok(Some(message)) => message,
+                                Ok(None) => continue,
+                                Err(e) => {
+                                    warn!("{} failed: {}", request, e);
+                                    MessageResponse::error {
+                                        request: request.to_owned(),
+                                        session_id,
+                                        code: e.code,
+                                        message: e.message,
+                                    }
                                 }
-                            },
+                            };
+                            let response = ResponseMessage::OutgoingServer {
+                                node: Some(server_id),
+                                wsid,
+                                request_id,
+                                message,
+                            };
+                            queue_write.push(response);
+                        },
                         }
                     },
-                    None => return Ok(())
+                    // sessions keep running; the caller reconnects and re-registers.
+                    None => return Err("api server closed the connection".into())
                 }
             },
+            Some(event) = events.recv() => {
+                let response = ResponseMessage::OutgoingServer {
+                    node: Some(server_id),
+                    wsid: None,
+                    request_id: None,
+                    message: event_message(event),
+                };
+                queue_write.push(response);
+            },
             res = queue_write.try_write() => res?,
+            _ = shutdown.cancelled() => {
+                // tell the api server first so it stops placing sessions here, then keep
+                // reporting while the running sessions end.
+                manager.drain();
+                queue_write.push(ResponseMessage::OutgoingServer {
+                    node: Some(server_id),
+                    wsid: None,
+                    request_id: None,
+                    message: MessageResponse::unregisterMixingServer {
+                        mode: config.clone().mode,
+                        region: config.clone().region,
+                    },
+                });
+                let ended = manager.shutdown(config.shutdown_timeout);
+                pin!(ended);
+                loop {
+                    select! {
+                        _ = &mut ended => break,
+                        Some(event) = events.recv() => {
+                            queue_write.push(ResponseMessage::OutgoingServer {
+                                node: Some(server_id),
+                                wsid: None,
+                                request_id: None,
+                                message: event_message(event),
+                            });
+                        },
+                        res = queue_write.try_write() => res?,
+                    }
+                }
+                while let Ok(event) = events.try_recv() {
+                    queue_write.push(ResponseMessage::OutgoingServer {
+                        node: Some(server_id),
+                        wsid: None,
+                        request_id: None,
+                        message: event_message(event),
+                    });
+                }
+                queue_write.flush().await?;
+                return Ok(());
+            },
+            _ = dead.as_mut() => {
+                return Err(format!(
+                    "api server sent nothing for {:?}",
+                    config.keepalive_timeout
+                ).into());
+            },
             _ = timer.as_mut() => {
-                // check for keep alive state.
+                if last_seen.elapsed() >= config.heartbeat_interval {
+                    queue_write.push(ResponseMessage::OutgoingServer {
+                        node: Some(server_id),
+                        wsid: None,
+                        request_id: None,
+                        message: MessageResponse::ping {},
+                    });
+                }
                 // send heart beat message.
+                let server_load = load::measure(&manager, &mut cpu_monitor);
                 let response = ResponseMessage::OutgoingServer {
                     node: Some(server_id),
+                    wsid: None,
+                    request_id: None,
                     message: MessageResponse::serverLoad {
                         mode: config.clone().mode,
-                        region: config.clone().mode,
-                        load:  0.0
+                        region: config.clone().region,
+                        load: server_load.load,
+                        sessions: server_load.sessions,
+                        inputs: server_load.inputs,
+                        cpu: server_load.cpu,
+                        available_ports: server_load.available_ports,
                     },
                 };
                 queue_write.push(response);
 
                 // reset timer.
-                timer.as_mut().reset(Instant::now() + Duration::from_secs(10));
+                timer.as_mut().reset(Instant::now() + config.heartbeat_interval);
             }
         }
     }
 }
 
+// carry out one control request and build the response to it, if it gets one.
+async fn handle_request(
+    message: MessageRequest,
+    manager: &Arc<MixerSessionManager>,
+    config: &Config,
+    media_ip: IpAddr,
+) -> Result<Option<MessageResponse>, MixerError> {
+    let response = match message {
+        MessageRequest::createFrameAudioMixer {
+            session_id,
+            producers,
+            destination_ip,
+            destination_port,
+            codec,
+            mix_minus,
+            spatial,
+        } => {
+            let codec = codec.unwrap_or_else(|| config.codec.clone());
+            let ports = manager.create_session(
+                session_id.clone(),
+                producers,
+                &destination_ip,
+                destination_port,
+                &codec,
+                mix_minus,
+                spatial,
+            )?;
+            info!("createFrameAudioMixer {}", &session_id);
+            if let Err(e) = manager.start_session(&session_id) {
+                let _ = manager.remove_session(&session_id);
+                return Err(e);
+            }
+            let outputs = manager.get_session(&session_id)?.outputs;
+            MessageResponse::createdFrameAudioMixer {
+                session_id,
+                ip: media_ip,
+                port_range: ports,
+                destination_port,
+                outputs,
+            }
+        }
+        MessageRequest::destroyFrameAudioMixer { session_id } => {
+            let stats = manager.destroy_session(&session_id).await?;
+            info!("destroyedFrameAudioMixer {}", &session_id);
+            MessageResponse::destroyedFrameAudioMixer { session_id, stats }
+        }
+        MessageRequest::listFrameAudioMixers {} => MessageResponse::frameAudioMixers {
+            sessions: manager.list_sessions(),
+        },
+        MessageRequest::getFrameAudioMixer { session_id } => MessageResponse::frameAudioMixer {
+            session: manager.get_session(&session_id)?,
+        },
+        MessageRequest::addMixerInput {
+            session_id,
+            destination_port,
+        } => {
+            let (port, output) = manager.add_input(&session_id, destination_port)?;
+            MessageResponse::mixerInputAdded {
+                session_id,
+                port,
+                output,
+            }
+        }
+        MessageRequest::setMixerInput {
+            session_id,
+            port,
+            gain_db,
+            mute,
+            solo,
+        } => {
+            let input = manager.set_input(&session_id, port, gain_db, mute, solo)?;
+            MessageResponse::mixerInputUpdated { session_id, input }
+        }
+        MessageRequest::startRecording {
+            session_id,
+            format,
+            max_duration,
+            max_size,
+            multitrack,
+        } => {
+            let recording = manager.start_recording(
+                &session_id,
+                &config.recording.dir,
+                format,
+                max_duration.or(config.recording.max_duration),
+                max_size.or(config.recording.max_size),
+                multitrack,
+            )?;
+            MessageResponse::recordingStarted {
+                session_id,
+                recording,
+            }
+        }
+        MessageRequest::stopRecording { session_id } => {
+            manager.stop_recording(&session_id)?;
+            MessageResponse::recordingStopped { session_id }
+        }
+        // sent many times a second, so only failures are answered.
+        MessageRequest::updatePositions {
+            session_id,
+            positions,
+        } => {
+            manager.update_positions(&session_id, &positions)?;
+            return Ok(None);
+        }
+        MessageRequest::ping {} => MessageResponse::pong {},
+        // the answer to our own ping, nothing to reply.
+        MessageRequest::pong {} => return Ok(None),
+        MessageRequest::removeMixerInput { session_id, port } => {
+            // stopping the branch waits on its streaming threads.
+            let manager = manager.clone();
+            let id = session_id.clone();
+            tokio::task::spawn_blocking(move || manager.remove_input(&id, port))
+                .await
+                .map_err(|e| MixerError::new(ErrorCode::PipelineFailure, e.to_string()))??;
+            MessageResponse::mixerInputRemoved { session_id, port }
+        }
+    };
+    Ok(Some(response))
+}
+
+// answer a message that couldn't be decoded, echoing whatever of it can be made out.
+fn malformed_response(server_id: Uuid, e: FrameError) -> ResponseMessage {
+    let value = match &e {
+        FrameError::Json { frame, .. } => serde_json::from_slice::<Value>(frame).ok(),
+        FrameError::TooLarge { .. } => None,
+    };
+    let field = |value: Option<&Value>, key: &str| {
+        value
+            .and_then(|value| value.get(key))
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+    };
+    let message = value.as_ref().and_then(|value| value.get("message"));
+    ResponseMessage::OutgoingServer {
+        node: Some(server_id),
+        wsid: field(value.as_ref(), "wsid"),
+        request_id: field(value.as_ref(), "requestId"),
+        message: MessageResponse::error {
+            request: field(message, "type").unwrap_or_default(),
+            session_id: field(message, "sessionId"),
+            code: ErrorCode::MalformedMessage,
+            message: e.to_string(),
+        },
+    }
+}
+
+fn event_message(event: MixerEvent) -> MessageResponse {
+    match event {
+        MixerEvent::Started { session_id } => MessageResponse::mixerStarted { session_id },
+        MixerEvent::Failed { session_id, error } => {
+            MessageResponse::mixerFailed { session_id, error }
+        }
+        MixerEvent::Ended { session_id } => MessageResponse::mixerEnded { session_id },
+        MixerEvent::InputJoined {
+            session_id,
+            port,
+            ssrc,
+        } => MessageResponse::inputJoined {
+            session_id,
+            port,
+            ssrc,
+        },
+        MixerEvent::InputLost {
+            session_id,
+            port,
+            ssrc,
+        } => MessageResponse::inputLost {
+            session_id,
+            port,
+            ssrc,
+        },
+        MixerEvent::RecordingCompleted {
+            session_id,
+            path,
+            duration,
+            size,
+        } => MessageResponse::recordingCompleted {
+            session_id,
+            path,
+            duration,
+            size,
+        },
+    }
+}
+
 pub struct QueuedWrite<'a, S> {
     write: Pin<&'a mut S>,
     queue: VecDeque<ResponseMessage>,
@@ -151,22 +439,34 @@ where
     // try to wire message in queue to io.
     pub async fn try_write(&mut self) -> Result<(), Error> {
         loop {
-            match (self.is_flush, self.queue.is_empty()) {
-                (false, true) => return poll_fn(|_| Poll::Pending).await,
-                (false, false) => {
-                    poll_fn(|cx| {
-                        ready!(self.write.as_mut().poll_ready(cx))?;
-                        let msg = self.pop();
-                        Poll::Ready(self.write.as_mut().start_send(msg))
-                    })
-                    .await?;
-                    self.is_flush = true;
-                }
-                (true, _) => {
-                    poll_fn(|cx| self.write.as_mut().poll_flush(cx)).await?;
-                    self.is_flush = false;
-                }
+            if !self.is_flush && self.queue.is_empty() {
+                return poll_fn(|_| Poll::Pending).await;
             }
+            self.write_next().await?;
+        }
+    }
+
+    // write out everything queued so far and return once it is flushed.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        while self.is_flush || !self.queue.is_empty() {
+            self.write_next().await?;
+        }
+        Ok(())
+    }
+
+    async fn write_next(&mut self) -> Result<(), Error> {
+        if self.is_flush {
+            poll_fn(|cx| self.write.as_mut().poll_flush(cx)).await?;
+            self.is_flush = false;
+        } else {
+            poll_fn(|cx| {
+                ready!(self.write.as_mut().poll_ready(cx))?;
+                let msg = self.pop();
+                Poll::Ready(self.write.as_mut().start_send(msg))
+            })
+            .await?;
+            self.is_flush = true;
         }
+        Ok(())
     }
 }
@@ -0,0 +1,125 @@
+use crate::mixer::session_manager::MixerSessionManager;
+use std::{collections::HashMap, fs, time::Instant};
+
+// USER_HZ, the unit of utime/stime in /proc/<pid>/task/<tid>/stat.
+const CLOCK_TICKS_PER_SEC: f32 = 100.0;
+
+// samples how much cpu the gstreamer streaming threads used between heartbeats.
+// every thread of the process except the main thread and the tokio workers is
+// owned by gstreamer, so those are the ones summed up.
+pub struct CpuMonitor {
+    last_sample: Option<Instant>,
+    thread_ticks: HashMap<u32, u64>,
+}
+
+impl CpuMonitor {
+    pub fn new() -> Self {
+        let mut monitor = Self {
+            last_sample: None,
+            thread_ticks: HashMap::new(),
+        };
+        // take the baseline now so the first heartbeat already has a real figure.
+        monitor.sample();
+        monitor
+    }
+
+    // fraction (0.0 - 1.0) of the machine's cpu used since the previous call.
+    pub fn sample(&mut self) -> f32 {
+        let now = Instant::now();
+        let thread_ticks = streaming_thread_ticks();
+        let used_ticks: u64 = thread_ticks
+            .iter()
+            .map(|(tid, ticks)| {
+                let last = self.thread_ticks.get(tid).copied().unwrap_or(0);
+                ticks.saturating_sub(last)
+            })
+            .sum();
+        let last_sample = self.last_sample.replace(now);
+        self.thread_ticks = thread_ticks;
+
+        let elapsed = match last_sample {
+            Some(last_sample) => now.duration_since(last_sample).as_secs_f32(),
+            None => return 0.0,
+        };
+        let cpus = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1) as f32;
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+        (used_ticks as f32 / CLOCK_TICKS_PER_SEC / elapsed / cpus).min(1.0)
+    }
+}
+
+// what the node reports in its serverLoad heartbeat.
+pub struct ServerLoad {
+    pub sessions: usize,
+    pub inputs: usize,
+    pub cpu: f32,
+    pub available_ports: usize,
+    // the busier of cpu and port usage, which ever runs out first limits the node.
+    pub load: f32,
+}
+
+pub fn measure(manager: &MixerSessionManager, cpu: &mut CpuMonitor) -> ServerLoad {
+    let (sessions, inputs) = manager.session_counts();
+    let (available_ports, port_capacity) = manager.port_capacity();
+    let cpu = cpu.sample();
+    let port_usage = if port_capacity == 0 {
+        1.0
+    } else {
+        1.0 - available_ports as f32 / port_capacity as f32
+    };
+    ServerLoad {
+        sessions,
+        inputs,
+        cpu,
+        available_ports,
+        load: cpu.max(port_usage),
+    }
+}
+
+// utime + stime of every gstreamer thread, by thread id.
+fn streaming_thread_ticks() -> HashMap<u32, u64> {
+    let pid = std::process::id();
+    let mut ticks = HashMap::new();
+    let tasks = match fs::read_dir("/proc/self/task") {
+        Ok(tasks) => tasks,
+        Err(_) => return ticks,
+    };
+    for task in tasks.flatten() {
+        let tid = match task
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        {
+            Some(tid) => tid,
+            None => continue,
+        };
+        if tid == pid {
+            continue;
+        }
+        let stat = match fs::read_to_string(task.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        if let Some((name, used)) = parse_stat(&stat) {
+            if !name.starts_with("tokio-runtime") {
+                ticks.insert(tid, used);
+            }
+        }
+    }
+    ticks
+}
+
+// "tid (comm) state ppid ... utime stime ...", comm may itself contain spaces.
+fn parse_stat(stat: &str) -> Option<(&str, u64)> {
+    let open = stat.find('(')?;
+    let close = stat.rfind(')')?;
+    let name = &stat[open + 1..close];
+    let fields: Vec<&str> = stat[close + 1..].split_whitespace().collect();
+    // fields[0] is the state (field 3), utime and stime are fields 14 and 15.
+    let utime = fields.get(11)?.parse::<u64>().ok()?;
+    let stime = fields.get(12)?.parse::<u64>().ok()?;
+    Some((name, utime + stime))
+}
@@ -1,66 +1,148 @@
+use auth::Authenticator;
+use backoff::Backoff;
 use clap::Parser;
-use config::Config;
-use std::{
-    net::{SocketAddr, ToSocketAddrs},
-    str::FromStr,
-    time::Duration,
+use config::{Args, Config};
+use mixer::session_manager::MixerSessionManager;
+use std::sync::Arc;
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+    sync::mpsc::unbounded_channel,
+    time::{sleep, Instant},
 };
-use tokio::{net::TcpStream, time::sleep};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
+use transport::Connector;
 
 use crate::handler::handle_stream;
 
+mod auth;
+mod backoff;
 mod codec;
 mod config;
 mod handler;
+mod load;
 mod message;
 mod mixer;
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
-    #[clap(short, long)]
-    url: String,
-}
+mod transport;
 
 #[tokio::main]
 async fn main() {
+    let args: Args = Args::parse();
+    let config = match Config::load(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
     tracing_subscriber::fmt()
-        .with_env_filter("frame_mediasoup=info,frame_mediasoup_proto=trace")
+        .with_env_filter(config.log.as_str())
         .init();
-    let args: Args = Args::parse();
-    let config = Config::init();
-    let addr = args
-        .url
-        .to_socket_addrs()
-        .unwrap()
-        .find(|addr| addr.is_ipv4())
-        .unwrap_or_else(|| {
-            let default = "0.0.0.0:1188";
-            info!(
-                "Can not resolve ipv4 address from given url. Fall back to default value: {}",
-                default
-            );
-            SocketAddr::from_str(default).unwrap()
-        });
+    info!(
+        "Node {} in region {}, ports {:?}, public ip {:?}",
+        config.node, config.region, config.port_range, config.public_ip
+    );
+    gstreamer::init().expect("failed to initialize gstreamer");
+    // sessions outlive any single request, so the node keeps one manager for all of them.
+    // pipeline events are queued here until a connection to the api server can carry them.
+    let (events_tx, mut events) = unbounded_channel();
+    let manager = Arc::new(MixerSessionManager::new(config.port_range, events_tx));
+    let connector = match Connector::new(&config) {
+        Ok(connector) => connector,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let auth = config
+        .auth_secret
+        .as_deref()
+        .map(|secret| Authenticator::new(secret, config.node, config.verify_requests));
 
-    tokio::spawn(async move {
-        loop {
-            info!("Connecting to: {}", &addr);
+    // cancelled on SIGTERM/SIGINT: the node drains its sessions and exits.
+    let shutdown = CancellationToken::new();
+    tokio::spawn(cancel_on_signal(shutdown.clone()));
 
-            match TcpStream::connect(addr).await {
-                Ok(stream) => match handle_stream(stream, config.clone()).await {
-                    Ok(_) => {
-                        info!("Shutting down application");
-                        return;
+    let shutdown_timeout = config.shutdown_timeout;
+    let connection = {
+        let manager = manager.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let mut backoff = Backoff::new(
+                config.reconnect_delay,
+                config.reconnect_max_delay,
+                config.reconnect_max_attempts,
+            );
+            loop {
+                info!("Connecting to: {}", &config.url);
+
+                // resolve on every attempt, the api server may have moved.
+                let connected = select! {
+                    connected = connector.connect() => connected,
+                    _ = shutdown.cancelled() => return true,
+                };
+                match connected {
+                    Ok(connection) => {
+                        let connected_at = Instant::now();
+                        let result = handle_stream(
+                            connection,
+                            config.clone(),
+                            manager.clone(),
+                            &mut events,
+                            &shutdown,
+                            auth.as_ref(),
+                        )
+                        .await;
+                        // a server that accepts and hangs up right away must still use up
+                        // the attempts; only a connection that outlived the keepalive
+                        // timeout was really up.
+                        if connected_at.elapsed() > config.keepalive_timeout {
+                            backoff.reset();
+                        }
+                        match result {
+                            Ok(_) => {
+                                info!("Shutting down application");
+                                return true;
+                            }
+                            Err(e) => error!("Tcp handle error: {:?}", e),
+                        }
                     }
-                    Err(e) => error!("Tcp handle error: {:?}", e),
-                },
-                Err(e) => error!("Tcp connect error: {:?}", e),
+                    Err(e) => error!("Tcp connect error: {:?}", e),
+                }
+
+                let Some(delay) = backoff.next_delay() else {
+                    error!(
+                        "Giving up on {} after {} reconnect attempts",
+                        config.url,
+                        backoff.attempts()
+                    );
+                    return false;
+                };
+                info!("Reconnecting in {:?}", delay);
+                select! {
+                    _ = sleep(delay) => {},
+                    _ = shutdown.cancelled() => return true,
+                }
             }
+        })
+    };
+    let clean = connection.await.expect("netsocket error");
 
-            sleep(Duration::from_secs(2)).await;
-        }
-    })
-    .await
-    .expect("netsocket error");
+    // the connection already ended the sessions if it was up; otherwise end them here.
+    manager.shutdown(shutdown_timeout).await;
+    if !clean {
+        std::process::exit(1);
+    }
+    info!("Shut down");
+}
+
+async fn cancel_on_signal(shutdown: CancellationToken) {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = terminate.recv() => info!("Received SIGTERM"),
+    }
+    shutdown.cancel();
 }
@@ -1,6 +1,8 @@
 #![allow(non_snake_case, non_camel_case_types)]
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{convert::TryFrom, net::IpAddr};
 use uuid::Uuid;
 
 /*
@@ -11,29 +13,188 @@ destroyedFrameAudioMixer (mixer->api)
 // incoming
 createFrameAudioMixer (api -> mixer) (edited)
 destroyFrameAudioMixer (api -> mixer)
+listFrameAudioMixers / getFrameAudioMixer (api -> mixer)
+frameAudioMixers / frameAudioMixer (mixer -> api)
+addMixerInput / removeMixerInput (api -> mixer)
+mixerInputAdded / mixerInputRemoved (mixer -> api)
+setMixerInput (api -> mixer) gain, mute and solo of one producer, answered with mixerInputUpdated
+error (mixer -> api) in place of the response to a failed request
+mixerStarted / mixerFailed / mixerEnded / inputJoined / inputLost (mixer -> api) unprompted
 heartbeat (mixer -> api)
+updatePositions (api -> mixer) for spatial sessions, only answered with error
+startRecording / stopRecording (api -> mixer) answered with recordingStarted / recordingStopped
+recordingCompleted (mixer -> api) unprompted for every file a recording closes
+ping / pong (either way) keepalive, answered with pong
+unregisterMixingServer (mixer -> api) when the node starts draining before shutdown
 createFrameAudioMixer will open a port for incoming RTP from mediasoup, mediasoup would then send rtp packets when received createdFrameAudioMixer
  */
 
 // server message sent to client
 #[derive(Serialize, Deserialize, Debug)]
-#[serde(untagged)]
+#[serde(untagged, try_from = "ReceivedMessage")]
 pub enum RequestMessage {
     IncomingServer {
         node: Option<Uuid>,
         wsid: Option<String>,
+        // set by the api server to match the response to this request.
+        #[serde(rename = "requestId")]
+        request_id: Option<String>,
+        // required when the node verifies requests, see `auth`.
+        auth: Option<AuthToken>,
         message: MessageRequest,
+        // hex sha-256 of `message` as received, with its keys sorted and no whitespace.
+        // Requests are signed over it.
+        #[serde(skip)]
+        digest: String,
     },
 }
 
+// a request as read off the connection, `message` kept as json until it is digested.
+#[derive(Deserialize)]
+struct ReceivedMessage {
+    node: Option<Uuid>,
+    wsid: Option<String>,
+    #[serde(rename = "requestId")]
+    request_id: Option<String>,
+    auth: Option<AuthToken>,
+    message: serde_json::Value,
+}
+
+impl TryFrom<ReceivedMessage> for RequestMessage {
+    type Error = serde_json::Error;
+
+    fn try_from(received: ReceivedMessage) -> Result<Self, Self::Error> {
+        // serde_json keeps object keys sorted, so this is the canonical form.
+        let digest = hex::encode(Sha256::digest(received.message.to_string()));
+        Ok(RequestMessage::IncomingServer {
+            node: received.node,
+            wsid: received.wsid,
+            request_id: received.request_id,
+            auth: received.auth,
+            message: serde_json::from_value(received.message)?,
+            digest,
+        })
+    }
+}
+
 // server message sent to client
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum MessageRequest {
     #[serde(rename_all = "camelCase")]
-    createFrameAudioMixer { hello: String },
+    createFrameAudioMixer {
+        session_id: String,
+        producers: usize,
+        destination_ip: String,
+        destination_port: u16,
+        // falls back to the node's configured codec.
+        codec: Option<CodecPreferences>,
+        // send every producer a mix of the others, to destinationPort, destinationPort + 1, ...
+        // in the order of the allocated ports, instead of one mix of all to destinationPort.
+        #[serde(default)]
+        mix_minus: bool,
+        // pan and attenuate every producer in each mix by where they are relative to
+        // its listener, see updatePositions. Needs mixMinus.
+        spatial: Option<SpatialSettings>,
+    },
+    #[serde(rename_all = "camelCase")]
+    destroyFrameAudioMixer {
+        session_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    listFrameAudioMixers {},
+    #[serde(rename_all = "camelCase")]
+    getFrameAudioMixer {
+        session_id: String,
+    },
     #[serde(rename_all = "camelCase")]
-    destroyFrameAudioMixer {},
+    addMixerInput {
+        session_id: String,
+        // where a mix-minus session sends the new producer's mix, by default the
+        // lowest port from the session's destinationPort not already sent to.
+        destination_port: Option<u16>,
+    },
+    #[serde(rename_all = "camelCase")]
+    removeMixerInput {
+        session_id: String,
+        port: u16,
+    },
+    // change how one producer is heard in every mix, ramped to the new level.
+    // Fields left out keep their current value.
+    #[serde(rename_all = "camelCase")]
+    setMixerInput {
+        session_id: String,
+        port: u16,
+        gain_db: Option<f64>,
+        mute: Option<bool>,
+        solo: Option<bool>,
+    },
+    // write a mix of every producer to files in the node's recording directory,
+    // starting a new file once maxDuration seconds or maxSize bytes are reached.
+    // Limits left out are taken from the node's config. multitrack also writes
+    // each producer to a file of its own, listed in a json manifest.
+    #[serde(rename_all = "camelCase")]
+    startRecording {
+        session_id: String,
+        #[serde(default)]
+        format: RecordingFormat,
+        max_duration: Option<u64>,
+        max_size: Option<u64>,
+        #[serde(default)]
+        multitrack: bool,
+    },
+    // close the file being written; its recordingCompleted follows once it is.
+    #[serde(rename_all = "camelCase")]
+    stopRecording {
+        session_id: String,
+    },
+    // where producers are and which way they face, sent as often as they move.
+    // Only answered when it fails.
+    #[serde(rename_all = "camelCase")]
+    updatePositions {
+        session_id: String,
+        positions: Vec<Position>,
+    },
+    ping {},
+    // the api server answering our ping.
+    pong {},
+}
+
+impl MessageRequest {
+    // the request's type as it appears on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageRequest::createFrameAudioMixer { .. } => "createFrameAudioMixer",
+            MessageRequest::destroyFrameAudioMixer { .. } => "destroyFrameAudioMixer",
+            MessageRequest::listFrameAudioMixers { .. } => "listFrameAudioMixers",
+            MessageRequest::getFrameAudioMixer { .. } => "getFrameAudioMixer",
+            MessageRequest::addMixerInput { .. } => "addMixerInput",
+            MessageRequest::removeMixerInput { .. } => "removeMixerInput",
+            MessageRequest::setMixerInput { .. } => "setMixerInput",
+            MessageRequest::updatePositions { .. } => "updatePositions",
+            MessageRequest::startRecording { .. } => "startRecording",
+            MessageRequest::stopRecording { .. } => "stopRecording",
+            MessageRequest::ping {} => "ping",
+            MessageRequest::pong {} => "pong",
+        }
+    }
+
+    pub fn session_id(&self) -> Option<&str> {
+        match self {
+            MessageRequest::createFrameAudioMixer { session_id, .. }
+            | MessageRequest::destroyFrameAudioMixer { session_id }
+            | MessageRequest::getFrameAudioMixer { session_id }
+            | MessageRequest::addMixerInput { session_id, .. }
+            | MessageRequest::removeMixerInput { session_id, .. }
+            | MessageRequest::setMixerInput { session_id, .. }
+            | MessageRequest::updatePositions { session_id, .. }
+            | MessageRequest::startRecording { session_id, .. }
+            | MessageRequest::stopRecording { session_id } => Some(session_id),
+            MessageRequest::listFrameAudioMixers {}
+            | MessageRequest::ping {}
+            | MessageRequest::pong {} => None,
+        }
+    }
 }
 
 // client message sent to server
@@ -42,6 +203,11 @@ pub enum MessageRequest {
 pub enum ResponseMessage {
     OutgoingServer {
         node: Option<Uuid>,
+        // wsid and requestId of the request being answered, if any.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        wsid: Option<String>,
+        #[serde(rename = "requestId", skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
         message: MessageResponse,
     },
 }
@@ -50,18 +216,334 @@ pub enum ResponseMessage {
 #[serde(tag = "type")]
 pub enum MessageResponse {
     #[serde(rename_all = "camelCase")]
-    registerMixingServer { mode: String, region: String },
+    registerMixingServer {
+        mode: String,
+        region: String,
+        // where mediasoup should point its PlainTransports.
+        ip: IpAddr,
+        // [first, last] udp port handed out to inputs and how many of them there are.
+        port_range: (u16, u16),
+        port_capacity: usize,
+        codecs: Vec<CodecCapability>,
+        version: String,
+        // sessions already running here, so a reconnecting node can be reconciled.
+        sessions: Vec<SessionInfo>,
+        // proves the node knows the shared secret, absent when none is configured.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<AuthToken>,
+    },
+    // the node is shutting down: no new sessions, the running ones are being ended.
+    #[serde(rename_all = "camelCase")]
+    unregisterMixingServer {
+        mode: String,
+        region: String,
+    },
+    // sent when the api server has been quiet for a heartbeat; it answers with pong.
+    ping {},
+    pong {},
     #[serde(rename_all = "camelCase")]
     serverLoad {
         mode: String,
         region: String,
         load: f32,
+        sessions: usize,
+        inputs: usize,
+        cpu: f32,
+        available_ports: usize,
     },
     #[serde(rename_all = "camelCase")]
     createdFrameAudioMixer {
+        session_id: String,
+        ip: IpAddr,
         port_range: Vec<u16>,
         destination_port: u16,
+        // one entry for the full mix, or with mixMinus one per producer port.
+        outputs: Vec<MixerOutput>,
+    },
+    #[serde(rename_all = "camelCase")]
+    destroyedFrameAudioMixer {
+        session_id: String,
+        stats: SessionStats,
+    },
+    #[serde(rename_all = "camelCase")]
+    frameAudioMixers {
+        sessions: Vec<SessionInfo>,
+    },
+    #[serde(rename_all = "camelCase")]
+    frameAudioMixer {
+        session: SessionInfo,
+    },
+    #[serde(rename_all = "camelCase")]
+    mixerInputAdded {
+        session_id: String,
+        port: u16,
+        // the new producer's own mix in a mix-minus session.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output: Option<MixerOutput>,
+    },
+    #[serde(rename_all = "camelCase")]
+    mixerInputRemoved {
+        session_id: String,
+        port: u16,
+    },
+    #[serde(rename_all = "camelCase")]
+    mixerInputUpdated {
+        session_id: String,
+        input: MixerInput,
+    },
+    #[serde(rename_all = "camelCase")]
+    recordingStarted {
+        session_id: String,
+        recording: RecordingInfo,
+    },
+    #[serde(rename_all = "camelCase")]
+    recordingStopped {
+        session_id: String,
+    },
+    // sent unprompted as a session's pipeline changes.
+    #[serde(rename_all = "camelCase")]
+    mixerStarted {
+        session_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    mixerFailed {
+        session_id: String,
+        error: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    mixerEnded {
+        session_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    inputJoined {
+        session_id: String,
+        port: u16,
+        ssrc: u32,
+    },
+    #[serde(rename_all = "camelCase")]
+    inputLost {
+        session_id: String,
+        port: u16,
+        ssrc: u32,
+    },
+    // a recording file was closed, on rotation, stopRecording or the end of the session.
+    #[serde(rename_all = "camelCase")]
+    recordingCompleted {
+        session_id: String,
+        path: String,
+        // seconds of audio in the file.
+        duration: f64,
+        size: u64,
     },
+    // a request could not be carried out, sent in place of its normal response.
     #[serde(rename_all = "camelCase")]
-    destroyFrameAudioMixer {},
+    error {
+        request: String,
+        session_id: Option<String>,
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+// codec parameters of the rtp streams coming in from and going back out to mediasoup
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CodecPreferences {
+    pub payload_type: u8,
+    pub clock_rate: i32,
+    pub bitrate: i32,
+}
+
+impl CodecPreferences {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.payload_type > 127 {
+            return Err(format!(
+                "{} is not a valid rtp payload type",
+                self.payload_type
+            ));
+        }
+        if self.clock_rate <= 0 || self.bitrate <= 0 {
+            return Err("clock rate and bitrate must be positive".to_owned());
+        }
+        Ok(())
+    }
+}
+
+impl Default for CodecPreferences {
+    fn default() -> Self {
+        Self {
+            payload_type: 100,
+            clock_rate: 48000,
+            bitrate: 48000,
+        }
+    }
+}
+
+// what a session did over its lifetime, reported when it is destroyed
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionStats {
+    // seconds the pipeline has been playing
+    pub duration: f64,
+    pub inputs: usize,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+}
+
+// a running session as reported to listFrameAudioMixers/getFrameAudioMixer
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub port_range: Vec<u16>,
+    pub destination_ip: String,
+    pub destination_port: u16,
+    pub codec: CodecPreferences,
+    pub mix_minus: bool,
+    pub spatial: Option<SpatialSettings>,
+    pub inputs: Vec<MixerInput>,
+    pub outputs: Vec<MixerOutput>,
+    pub recording: Option<RecordingInfo>,
+    pub stats: SessionStats,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingFormat {
+    // opus in ogg, encoded at the session's bitrate.
+    #[default]
+    Opus,
+    // lossless flac in ogg.
+    Flac,
+}
+
+impl RecordingFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            RecordingFormat::Opus => "opus",
+            RecordingFormat::Flac => "oga",
+        }
+    }
+}
+
+// a recording underway. location is the pattern the files are named by, %03d
+// counting up from 0 with every new file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingInfo {
+    pub format: RecordingFormat,
+    pub location: String,
+    pub max_duration: Option<u64>,
+    pub max_size: Option<u64>,
+    // json listing every producer's track and when it joined and left, for multitrack.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<String>,
+}
+
+// how a producer is heard, as set by setMixerInput. While any producer of the
+// session is soloed, only the soloed ones are heard; mute wins over solo.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct InputControls {
+    pub gain_db: f64,
+    pub mute: bool,
+    pub solo: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MixerInput {
+    pub port: u16,
+    #[serde(flatten)]
+    pub controls: InputControls,
+}
+
+// how loudness falls off with distance in a spatial session, in room units. Producers
+// closer than refDistance are at full volume, beyond maxDistance they are silent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SpatialSettings {
+    pub ref_distance: f64,
+    pub max_distance: f64,
+    pub rolloff: f64,
+}
+
+impl SpatialSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ref_distance <= 0.0 || self.max_distance <= self.ref_distance {
+            return Err("spatial needs 0 < refDistance < maxDistance".to_owned());
+        }
+        if self.rolloff < 0.0 {
+            return Err("spatial rolloff must not be negative".to_owned());
+        }
+        Ok(())
+    }
+}
+
+impl Default for SpatialSettings {
+    fn default() -> Self {
+        Self {
+            ref_distance: 1.0,
+            max_distance: 50.0,
+            rolloff: 1.0,
+        }
+    }
+}
+
+// a producer's place in the room, y up. yaw is in radians; at 0 they face -z.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Position {
+    pub port: u16,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    #[serde(default)]
+    pub yaw: f64,
+}
+
+// one mix sent out by a session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MixerOutput {
+    // the producer whose mix this is, leaving them out; None for the full mix.
+    pub input_port: Option<u16>,
+    pub destination_port: u16,
+    pub ssrc: u32,
+}
+
+// a codec the mixer can receive and send, in mediasoup's terms
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CodecCapability {
+    pub mime_type: String,
+    pub clock_rate: i32,
+    pub channels: u8,
+}
+
+// hmac-sha256 of the shared secret over the node id, timestamp (unix seconds) and
+// nonce, and for requests also the requestId, request type and message digest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthToken {
+    pub node: Uuid,
+    pub timestamp: u64,
+    pub nonce: String,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    PortExhausted,
+    MissingElement,
+    BadParameters,
+    PipelineFailure,
+    SessionNotFound,
+    SessionExists,
+    Draining,
+    Unauthorized,
+    // a message that isn't json or isn't a known request, answered with `request` empty
+    // if not even its type could be read.
+    MalformedMessage,
 }
@@ -0,0 +1,204 @@
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::{
+    fs,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use tracing::warn;
+
+// what a multitrack recording wrote, kept next to its files so the tracks can be
+// lined up again. Rewritten on every change, so a crash still leaves it usable.
+// Changes are made under the mix lock on streaming threads, the file is written
+// by a task of its own.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    started: Instant,
+    #[serde(skip)]
+    updates: UnboundedSender<Vec<u8>>,
+    #[serde(skip)]
+    writer: JoinHandle<()>,
+    session_id: String,
+    // unix time in seconds the recording started; every offset counts from it.
+    started_at: f64,
+    stopped_at: Option<f64>,
+    mix: String,
+    tracks: Vec<ManifestTrack>,
+    events: Vec<ManifestEvent>,
+}
+
+// one input's file, from when its stream was first decoded until it left or the
+// recording stopped.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ManifestTrack {
+    port: u16,
+    ssrc: Option<u32>,
+    path: String,
+    joined_at: f64,
+    left_at: Option<f64>,
+}
+
+// a producer's rtp source appearing on or vanishing from its port.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ManifestEvent {
+    port: u16,
+    ssrc: u32,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    offset: f64,
+}
+
+impl Manifest {
+    pub fn new(path: PathBuf, session_id: &str, mix: &str) -> Self {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let (updates, pending) = unbounded_channel();
+        let writer = tokio::spawn(write_updates(path.clone(), pending));
+        Manifest {
+            path,
+            started: Instant::now(),
+            updates,
+            writer,
+            session_id: session_id.to_owned(),
+            started_at,
+            stopped_at: None,
+            mix: mix.to_owned(),
+            tracks: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    fn offset(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    pub fn track_count(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn add_track(&mut self, port: u16, ssrc: Option<u32>, path: &str) {
+        let joined_at = self.offset();
+        self.tracks.push(ManifestTrack {
+            port,
+            ssrc,
+            path: path.to_owned(),
+            joined_at,
+            left_at: None,
+        });
+        self.write();
+    }
+
+    // close the track at `path`, returning how long it ran.
+    pub fn end_track(&mut self, path: &str) -> Option<f64> {
+        let left_at = self.offset();
+        let track = self
+            .tracks
+            .iter_mut()
+            .find(|track| track.path == path && track.left_at.is_none())?;
+        track.left_at = Some(left_at);
+        let duration = left_at - track.joined_at;
+        self.write();
+        Some(duration)
+    }
+
+    pub fn source_event(&mut self, port: u16, ssrc: u32, joined: bool) {
+        let offset = self.offset();
+        self.events.push(ManifestEvent {
+            port,
+            ssrc,
+            kind: if joined { "joined" } else { "left" },
+            offset,
+        });
+        self.write();
+    }
+
+    // tracks still open end with the recording.
+    pub fn stop(&mut self) {
+        if self.stopped_at.is_some() {
+            return;
+        }
+        let offset = self.offset();
+        for track in self
+            .tracks
+            .iter_mut()
+            .filter(|track| track.left_at.is_none())
+        {
+            track.left_at = Some(offset);
+        }
+        self.stopped_at = Some(offset);
+        self.write();
+    }
+
+    // no more changes; the returned task ends once the last of them is written.
+    pub fn close(self) -> JoinHandle<()> {
+        self.writer
+    }
+
+    fn write(&self) {
+        match serde_json::to_vec_pretty(self) {
+            Ok(json) => {
+                let _ = self.updates.send(json);
+            }
+            Err(e) => warn!("can not serialize {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+// replace the file whole, readers never see half of it. Updates that queued up
+// while one was written are skipped for the newest.
+async fn write_updates(path: PathBuf, mut updates: UnboundedReceiver<Vec<u8>>) {
+    let tmp = path.with_extension("json.tmp");
+    while let Some(mut json) = updates.recv().await {
+        while let Ok(newer) = updates.try_recv() {
+            json = newer;
+        }
+        let written = match fs::write(&tmp, json).await {
+            Ok(()) => fs::rename(&tmp, &path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            warn!("can not write {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn written_once_closed() {
+        let path = std::env::temp_dir().join(format!("gmix-manifest-{}.json", std::process::id()));
+        let mut manifest = Manifest::new(path.clone(), "session", "mix.ogg");
+        manifest.add_track(5000, Some(7), "track-5000-0.ogg");
+        manifest.source_event(5000, 7, true);
+        manifest.add_track(5002, None, "track-5002-1.ogg");
+        assert!(manifest.end_track("track-5002-1.ogg").is_some());
+        manifest.stop();
+        manifest.close().await.unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written["sessionId"], "session");
+        assert_eq!(written["mix"], "mix.ogg");
+        let stopped_at = written["stoppedAt"].as_f64().unwrap();
+        let tracks = written["tracks"].as_array().unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0]["ssrc"], 7);
+        // the track still open ends with the recording.
+        assert_eq!(tracks[0]["leftAt"].as_f64(), Some(stopped_at));
+        assert!(tracks[1]["leftAt"].as_f64().unwrap() <= stopped_at);
+        assert_eq!(written["events"][0]["type"], "joined");
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+}
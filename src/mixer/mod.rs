@@ -1,21 +1,78 @@
+use crate::message::ErrorCode;
+use derive_more::Display;
+use std::error::Error;
+use tokio::sync::mpsc::UnboundedSender;
+
+pub mod manifest;
 pub mod pipeline;
 pub mod port_range_manager;
 pub mod session_manager;
+pub mod spatial;
+
+// something that happened to a running session, reported to the api server unprompted.
+#[derive(Debug, Clone)]
+pub enum MixerEvent {
+    Started {
+        session_id: String,
+    },
+    Failed {
+        session_id: String,
+        error: String,
+    },
+    Ended {
+        session_id: String,
+    },
+    InputJoined {
+        session_id: String,
+        port: u16,
+        ssrc: u32,
+    },
+    InputLost {
+        session_id: String,
+        port: u16,
+        ssrc: u32,
+    },
+    RecordingCompleted {
+        session_id: String,
+        path: String,
+        duration: f64,
+        size: u64,
+    },
+}
 
-pub struct MyError {
+pub type EventSender = UnboundedSender<MixerEvent>;
+
+// why a control request on a session could not be carried out.
+#[derive(Debug, Display)]
+#[display(fmt = "{:?}: {}", code, message)]
+pub struct MixerError {
+    pub code: ErrorCode,
     pub message: String,
 }
 
-impl std::fmt::Display for MyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "MyError: {}", self.message)
+impl MixerError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
     }
 }
 
-impl std::fmt::Debug for MyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "MyError {{ message: {} }}", self.message)
+impl Error for MixerError {}
+
+// errors coming out of gstreamer. Missing plugins are told apart so the api
+// server can stop placing sessions on a node that can never run them.
+impl From<Box<dyn Error + Send + Sync>> for MixerError {
+    fn from(e: Box<dyn Error + Send + Sync>) -> Self {
+        let e = match e.downcast::<MixerError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        if e.downcast_ref::<pipeline::MissingElement>().is_some() {
+            MixerError::new(ErrorCode::MissingElement, e.to_string())
+        } else {
+            MixerError::new(ErrorCode::PipelineFailure, e.to_string())
+        }
     }
 }
-
-impl std::error::Error for MyError {}
@@ -1,18 +1,32 @@
+use super::manifest::Manifest;
+use super::spatial;
+use super::{EventSender, MixerEvent};
+use crate::message::{
+    CodecPreferences, InputControls, MixerInput, MixerOutput, Position, RecordingFormat,
+    RecordingInfo, SessionStats, SpatialSettings,
+};
 use anyhow::Error;
 use derive_more::{Display, Error};
-use glib::translate::FromGlib;
+use futures::StreamExt;
 use gstreamer::traits::ElementExt;
 use gstreamer::{
-    element_error, prelude::*, Element, MessageView, PadProbeData, PadProbeReturn, PadProbeType,
+    element_error, prelude::*, MessageView, PadProbeData, PadProbeReturn, PadProbeType,
 };
-use gstreamer::{ElementFactory, Pipeline};
-use std::str::FromStr;
+use gstreamer::{Element, ElementFactory, Pad, Pipeline};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::{sync::Notify, task::JoinHandle};
+use tracing::{debug, info, trace, warn};
+
+// name of the application message posted on the bus to end `run`.
+const STOP_MESSAGE: &str = "gmix-stop";
 
 //Helper functions
 #[derive(Debug, Display, Error)]
 #[display(fmt = "Missing element {}", _0)]
-struct MissingElement(#[error(not(source))] &'static str);
+pub struct MissingElement(#[error(not(source))] &'static str);
 
 #[derive(Debug, Display, Error)]
 #[display(fmt = "Received error from {}: {} (debug: {:?})", src, error, debug)]
@@ -23,11 +37,6 @@ struct ErrorMessage {
     source: glib::Error,
 }
 
-#[cfg(feature = "v1_10")]
-#[derive(Clone, Debug, glib::Boxed)]
-#[boxed_type(name = "ErrorValue")]
-struct ErrorValue(Arc<Mutex<Option<Error>>>);
-
 #[derive(Debug, Display, Error)]
 #[display(fmt = "Unknown payload type {}", _0)]
 struct UnknownPT(#[error(not(source))] u32);
@@ -36,307 +45,443 @@ struct UnknownPT(#[error(not(source))] u32);
 #[display(fmt = "No such pad {} in {}", _0, _1)]
 struct NoSuchPad(#[error(not(source))] &'static str, String);
 
+// key of the one output mixing every input, when the session isn't mix-minus.
+// Input ports are never 0, so it can't collide with a participant's output.
+const FULL_MIX: u16 = 0;
+
+// elements receiving one producer's rtp stream on its allocated port.
+// the decode half is only built once rtpbin exposes the stream (see `add_input_branch`),
+// it ends in a tee feeding every output but the producer's own.
+struct InputBranch {
+    udpsrc: Element,
+    rtpbin: Element,
+    decoder: Vec<Element>,
+    tee: Option<Element>,
+    // output key -> the link from this input's tee into that output's mixer.
+    feeds: BTreeMap<u16, Feed>,
+    // into the recorder's mixer while the session is recording.
+    recording: Option<Feed>,
+    // of the decoded stream, once there is one.
+    ssrc: Option<u32>,
+    // the input on its own, while the session is recording multitrack.
+    track: Option<Track>,
+}
+
+// tee -> queue -> audioconvert -> encoder -> oggmux -> filesink, writing one input
+// as it was received. Like a recorder, removed once its eos reached the file.
+struct Track {
+    tee_pad: Pad,
+    elements: Vec<Element>,
+    path: String,
+    duration: f64,
+    removed: Arc<Notify>,
+}
+
+// tee -> queue [-> audiopanorama] -> mixer sink pad, carrying one input into one output.
+// The pad's volume and mute carry the input's controls; in a spatial session the volume
+// and the panorama also place the input for the listener.
+struct Feed {
+    tee_pad: Pad,
+    elements: Vec<Element>,
+    panorama: Option<Element>,
+    mixer_pad: Pad,
+}
+
+impl Feed {
+    fn pan(&self, pan: f32) {
+        if let Some(panorama) = &self.panorama {
+            panorama.set_property("panorama", pan);
+        }
+    }
+}
+
+// audiomixer -> audioconvert -> encoder -> splitmuxsink(oggmux), writing a mix of every
+// input to files. Once stopped it stays in the pipeline until its eos closed the last file.
+struct Recorder {
+    mixer: Element,
+    elements: Vec<Element>,
+    sink: Element,
+    info: RecordingInfo,
+    // location and running time at which the file being written was opened.
+    file: Option<(String, u64)>,
+    // feeds cut from their tees on stop, left in place for the eos to pass through.
+    stopped_feeds: Vec<Feed>,
+    removed: Arc<Notify>,
+    // tracks are named after it, see `Mix::link_track`.
+    prefix: String,
+    manifest: Option<Manifest>,
+}
+
+// how long a change of controls takes to reach its new level, in steps of `RAMP_STEP`.
+const RAMP_DURATION: Duration = Duration::from_millis(50);
+const RAMP_STEP: Duration = Duration::from_millis(5);
+
+// a change of controls on its way, fading every feed from the volume it had.
+struct Ramp {
+    id: u64,
+    started_at: Instant,
+    // (input, output key or None for the recorder) -> pad volume when the ramp started.
+    from: BTreeMap<(u16, Option<u16>), f64>,
+}
+
+// audiomixer -> opusenc -> opusparse -> rtpopuspay -> udpsink, sending one mix out.
+struct Output {
+    mixer: Element,
+    elements: Vec<Element>,
+    destination_port: u16,
+    ssrc: u32,
+}
+
+// inputs and outputs change together when feeds are linked, so they share a lock.
+#[derive(Default)]
+struct Mix {
+    inputs: BTreeMap<u16, InputBranch>,
+    // keyed by the input port whose participant listens to it, or `FULL_MIX`.
+    outputs: BTreeMap<u16, Output>,
+    spatial: Option<SpatialSettings>,
+    // last known position of each input's participant, as source and as listener.
+    positions: BTreeMap<u16, Position>,
+    // inputs whose controls were changed from the default.
+    controls: BTreeMap<u16, InputControls>,
+    ramp: Option<Ramp>,
+    ramps: u64,
+    recorder: Option<Recorder>,
+    stopped_recorders: Vec<Recorder>,
+    stopped_tracks: Vec<Track>,
+}
+
+impl Mix {
+    // link input `source` into output `listener` and place it there.
+    fn link_feed(&mut self, pipeline: &Pipeline, source: u16, listener: u16) -> Result<(), Error> {
+        let tee = match &self.inputs[&source].tee {
+            Some(tee) => tee.clone(),
+            None => return Ok(()),
+        };
+        let mixer = &self.outputs[&listener].mixer;
+        let feed = link_feed(pipeline, &tee, mixer, self.spatial.is_some())?;
+        self.set_level(source, Some(listener), &feed);
+        self.inputs
+            .get_mut(&source)
+            .unwrap()
+            .feeds
+            .insert(listener, feed);
+        Ok(())
+    }
+
+    // link input `source` into the recorder's mix, unplaced.
+    fn link_recording(&mut self, pipeline: &Pipeline, source: u16) -> Result<(), Error> {
+        let (tee, recorder) = match (&self.inputs[&source].tee, &self.recorder) {
+            (Some(tee), Some(recorder)) => (tee.clone(), recorder),
+            _ => return Ok(()),
+        };
+        let feed = link_feed(pipeline, &tee, &recorder.mixer, false)?;
+        self.set_level(source, None, &feed);
+        self.inputs.get_mut(&source).unwrap().recording = Some(feed);
+        Ok(())
+    }
+
+    // start a track of input `source` when recording multitrack.
+    fn link_track(&mut self, pipeline: &Pipeline, source: u16) -> Result<(), Error> {
+        let branch = &self.inputs[&source];
+        let (tee, recorder) = match (&branch.tee, &mut self.recorder) {
+            (Some(tee), Some(recorder)) => (tee, recorder),
+            _ => return Ok(()),
+        };
+        let manifest = match &mut recorder.manifest {
+            Some(manifest) => manifest,
+            None => return Ok(()),
+        };
+        let path = format!(
+            "{}-{}-{}.{}",
+            recorder.prefix,
+            source,
+            manifest.track_count(),
+            recorder.info.format.extension()
+        );
+        let track = build_track(pipeline, tee, recorder.info.format, &path)?;
+        manifest.add_track(source, branch.ssrc, &path);
+        self.inputs.get_mut(&source).unwrap().track = Some(track);
+        Ok(())
+    }
+
+    // cut a track off its input's `tee` and send it eos to close the file.
+    fn end_track(&mut self, tee: &Element, mut track: Track) -> Result<(), Error> {
+        let queue_sink = static_pad(&track.elements[0], "sink")?;
+        track.tee_pad.unlink(&queue_sink)?;
+        tee.release_request_pad(&track.tee_pad);
+        queue_sink.send_event(gstreamer::event::Eos::new());
+        if let Some(manifest) = self
+            .recorder
+            .as_mut()
+            .and_then(|recorder| recorder.manifest.as_mut())
+        {
+            track.duration = manifest.end_track(&track.path).unwrap_or_default();
+        }
+        self.stopped_tracks.push(track);
+        Ok(())
+    }
+
+    fn set_level(&self, source: u16, listener: Option<u16>, feed: &Feed) {
+        let (volume, pan) = self.level(source, listener);
+        feed.mixer_pad.set_property("volume", volume);
+        feed.mixer_pad.set_property("mute", !self.audible(source));
+        feed.pan(pan);
+    }
+
+    // every feed with its input and the participant hearing it, None for the recorder.
+    fn feeds(&self) -> impl Iterator<Item = (u16, Option<u16>, &Feed)> {
+        self.inputs.iter().flat_map(|(source, branch)| {
+            let outputs = branch
+                .feeds
+                .iter()
+                .map(move |(listener, feed)| (*source, Some(*listener), feed));
+            let recording = branch
+                .recording
+                .iter()
+                .map(move |feed| (*source, None, feed));
+            outputs.chain(recording)
+        })
+    }
+
+    // the recording ends with the pipeline, whether or not its files were closed.
+    fn stop_manifest(&mut self) {
+        if let Some(manifest) = self
+            .recorder
+            .as_mut()
+            .and_then(|recorder| recorder.manifest.as_mut())
+        {
+            manifest.stop();
+        }
+    }
+
+    // note a producer's source coming or going in the manifest, if there is one.
+    fn source_event(&mut self, port: u16, ssrc: u32, joined: bool) {
+        if let Some(manifest) = self
+            .recorder
+            .as_mut()
+            .and_then(|recorder| recorder.manifest.as_mut())
+        {
+            manifest.source_event(port, ssrc, joined);
+        }
+    }
+
+    // the recorder writing or closing files through `sink`.
+    fn recorder_mut(&mut self, sink: &gstreamer::Object) -> Option<&mut Recorder> {
+        self.recorder
+            .iter_mut()
+            .chain(self.stopped_recorders.iter_mut())
+            .find(|recorder| recorder.sink.upcast_ref::<gstreamer::Object>() == sink)
+    }
+
+    fn controls(&self, port: u16) -> InputControls {
+        self.controls.get(&port).cloned().unwrap_or_default()
+    }
+
+    fn audible(&self, source: u16) -> bool {
+        let controls = self.controls(source);
+        let soloing = self.controls.values().any(|controls| controls.solo);
+        !controls.mute && (controls.solo || !soloing)
+    }
+
+    // pad volume and pan of input `source` in the mix heard by `listener`.
+    fn level(&self, source: u16, listener: Option<u16>) -> (f64, f32) {
+        if !self.audible(source) {
+            return (0.0, 0.0);
+        }
+        let gain = 10f64.powf(self.controls(source).gain_db / 20.0);
+        match &self.spatial {
+            Some(settings) => {
+                let (volume, pan) = spatial::placement(
+                    settings,
+                    listener.and_then(|listener| self.positions.get(&listener)),
+                    self.positions.get(&source),
+                );
+                (volume * gain, pan)
+            }
+            None => (gain, 0.0),
+        }
+    }
+
+    // set every feed to its level, or the pan alone while a ramp is moving the volume.
+    fn place(&self) {
+        for (source, listener, feed) in self.feeds() {
+            let (volume, pan) = self.level(source, listener);
+            if self.ramp.is_none() {
+                feed.mixer_pad.set_property("volume", volume);
+            }
+            feed.pan(pan);
+        }
+    }
+
+    // start fading every feed from where it is now, superseding any ramp underway.
+    // Feeds becoming audible are unmuted at once, the others muted when it ends.
+    fn start_ramp(&mut self) -> u64 {
+        let mut from = BTreeMap::new();
+        for (source, listener, feed) in self.feeds() {
+            from.insert((source, listener), feed.mixer_pad.property::<f64>("volume"));
+            if self.audible(source) {
+                feed.mixer_pad.set_property("mute", false);
+            }
+        }
+        self.ramps += 1;
+        self.ramp = Some(Ramp {
+            id: self.ramps,
+            started_at: Instant::now(),
+            from,
+        });
+        self.ramps
+    }
+
+    // move the feeds one step along ramp `id`; false once it is done or superseded.
+    fn step_ramp(&mut self, id: u64) -> bool {
+        let ramp = match &self.ramp {
+            Some(ramp) if ramp.id == id => ramp,
+            _ => return false,
+        };
+        let t = (ramp.started_at.elapsed().as_secs_f64() / RAMP_DURATION.as_secs_f64()).min(1.0);
+        for (source, listener, feed) in self.feeds() {
+            let (target, _) = self.level(source, listener);
+            let from = ramp
+                .from
+                .get(&(source, listener))
+                .copied()
+                .unwrap_or(target);
+            feed.mixer_pad
+                .set_property("volume", from + (target - from) * t);
+            if t >= 1.0 {
+                feed.mixer_pad.set_property("mute", !self.audible(source));
+            }
+        }
+        if t >= 1.0 {
+            self.ramp = None;
+            return false;
+        }
+        true
+    }
+}
+
+type SharedMix = Arc<Mutex<Mix>>;
+
+// counters shared by the payloaders of every output.
+#[derive(Default)]
+struct SentCounters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
 pub struct AudioMixerPipeline {
+    session_id: String,
+    events: EventSender,
     pipeline: Arc<Mutex<Option<Pipeline>>>,
-    input_ports: Vec<u16>,
+    codec: CodecPreferences,
+    destination_ip: String,
+    mix_minus: bool,
+    mix: SharedMix,
+    started_at: Mutex<Option<Instant>>,
+    sent: Arc<SentCounters>,
 }
 
 impl AudioMixerPipeline {
+    // a full mix of every input to `destination_port`, or with `mix_minus` one mix
+    // per input leaving that input out, sent to consecutive ports from `destination_port`.
+    // `spatial` places the inputs in each of those mixes, see `update_positions`.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        session_id: &str,
+        events: EventSender,
         input_ports: Vec<u16>,
         destination_ip: &str,
         destination_port: u16,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+        codec: &CodecPreferences,
+        mix_minus: bool,
+        spatial: Option<SpatialSettings>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         gstreamer::init()?;
         let pipeline = Pipeline::new(Some("FrameMixerPipeline"));
-        let src = ElementFactory::make("udpsrc")
-            .build()
-            .expect("failed to create udpsrc");
-        let rtpbin = ElementFactory::make("rtpbin")
-            .build()
-            .expect("failed to create rtpbin");
+        // a recorder is only done once its sink's eos arrives, which the pipeline
+        // would otherwise keep to itself until every sink is.
+        pipeline.set_property("message-forward", true);
+        let sent = Arc::new(SentCounters::default());
+        let mix: SharedMix = Arc::new(Mutex::new(Mix {
+            spatial,
+            ..Mix::default()
+        }));
 
-        // create mixer
-        let audiomixer = ElementFactory::make("audiomixer")
-            .build()
-            .expect("failed to create audiomixer");
-        let opusenc = ElementFactory::make("opusenc")
-            .build()
-            .expect("failed to create OpusEnc");
-        let opusparseout = ElementFactory::make("opusparse")
-            .build()
-            .expect("failed to create OpusParseOut");
-        let rtpopuspay = ElementFactory::make("rtpopuspay")
-            .build()
-            .expect("failed to create RTPOpusPay");
-        let udpsink = ElementFactory::make("udpsink")
-            .build()
-            .expect("failed to create UDPSink");
-        let audio_caps = gstreamer::Caps::builder("application/x-rtp")
-            .field("media", "audio")
-            .field("clock-rate", 48000)
-            .field("encoding-name", "OPUS")
-            .build();
-        src.set_property("port", 1925); //TODO: Get this from signaling
-        src.set_property("caps", &audio_caps);
-
-        opusenc.set_property("bitrate", 48000);
-
-        udpsink.set_property("host", "127.0.0.1"); //TODO: Get this from signaling
-                                                   //udpsink.set_property("host", "127.0.0.1")?; //TODO: Get this from signaling
-        udpsink.set_property("port", 1928); //TODO: Get this from signaling
-                                            // Add elements to the pipeline
-        pipeline.add_many(&[
-            &src,
-            &rtpbin,
-            &audiomixer,
-            &opusenc,
-            &opusparseout,
-            &rtpopuspay,
-            &udpsink, //&oggmux,
-                      //&filesink
-        ])?;
-
-        gstreamer::Element::link_many(&[&src, &rtpbin])?;
-
-        // Respond to determining payload type (audio, video)
-        rtpbin.connect("request-pt-map", false, |values| {
-            let pt = values[2]
-                .get::<u32>()
-                .expect("rtpbin \"new-storage\" signal values[2]");
-            println!("RTPBin got payload of type {:?}", pt);
-            match pt {
-                100 => Some(
-                    gstreamer::Caps::builder("application/x-rtp")
-                        .field("media", "audio")
-                        .field("clock-rate", 48000i32)
-                        .field("encoding-name", "OPUS")
-                        .build()
-                        .to_value(),
-                ),
-                106 => Some(
-                    gstreamer::Caps::builder("application/x-rtp")
-                        .field("media", "video")
-                        .field("clock-rate", 90000i32)
-                        .field("encoding-name", "VP8")
-                        .build()
-                        .to_value(),
-                ),
-                _ => None,
-            }
-        });
-
-        //This is the outgoing SSRC to egress. Send this value to egres
-        // rtpbin.connect("on-new-sender-ssrc", false, |values| {
-        //     println!("@@ON NEW SENDER SSRC!!! {:?}", values);
-        //     if let [_, _, ssrc_value] = values {
-        //         if let Ok(ssrc) = ssrc_value.get::<u32>() {
-        //             println!("SSRC Value: {}", ssrc);
-        //         } else {
-        //             println!("Failed to extract SSRC value.");
-        //         }
-        //     }
-        //     None
-        // });
-
-        // This is incoming from ingress... dont use?
-        // rtpbin.connect("on-ssrc-validated", false, |values| {
-        //     println!("@@ON SSRC VALIDATED!!! {:?}", values);
-        //     if let [_, _, ssrc_value] = values {
-        //         if let Ok(ssrc) = ssrc_value.get::<u32>() {
-        //             println!("on new SSRC Value: {}", ssrc);
-        //         } else {
-        //             println!("Failed to extract SSRC value.");
-        //         }
-        //     }
-        //     None
-        // });
-        // let rtpbin_clone = rtpbin.clone();
-        // rtpbin.connect_pad_added(move |_element, new_pad| {
-        //     if new_pad.name() == "send_rtp_src_0" {
-        //         let srcpad = rtpbin_clone.static_pad("send_rtp_src_0").unwrap();
-        //         srcpad.add_probe(PadProbeType::BUFFER, |_pad, _info| {
-        //             if let Some(PadProbeData::Buffer(ref buffer)) = _info.data {
-        //                 // Use the buffer
-        //                 if let Some(map) = buffer.map_readable().ok() {
-        //                     let ssrc = u32::from_be_bytes([map[8], map[9], map[10], map[11]]);
-        //                     println!("&&&&&&& SSRC: {}", ssrc)
-        //                 }
-        //             }
-        //             PadProbeReturn::Ok
-        //         });
-        //     }
-        // });
-        let pipeline_clone = pipeline.clone();
-        let rtpopuspay_clone = rtpopuspay.clone();
-        // rtpbin.connect("on-ssrc-active", false, move |values| {
-        //     println!("@@ON SSRC on-ssrc-active!!! {:?}", values);
-        //     let elements = pipeline_clone.iterate_elements();
-        //     for element in elements {
-        //         println!("Element name: {:?}", element.unwrap().name());
-        //     }
-
-        //     if let [_, _, ssrc_value] = values {
-        //         if let Ok(ssrc) = ssrc_value.get::<u32>() {
-        //             println!("on new SSRC Value: {}", ssrc);
-        //         } else {
-        //             println!("Failed to extract SSRC value.");
-        //         }
-        //     }
-        //     None
-        // });
-
-        let rtpopuspay_srcpad = rtpopuspay_clone
-            .static_pad("src")
-            .expect("Failed to get src pad from rtpopuspay0");
-        rtpopuspay_srcpad.add_probe(PadProbeType::BUFFER, |_pad, info| {
-            if let Some(PadProbeData::Buffer(ref buffer)) = &info.data {
-                let map = buffer
-                    .map_readable()
-                    .expect("Failed to map buffer readable");
-                let ssrc = u32::from_be_bytes([map[8], map[9], map[10], map[11]]);
-                println!("@@@@@SSRC: {}", ssrc);
-                return PadProbeReturn::Remove;
-            }
-            PadProbeReturn::Ok
-        });
-
-        //This is a new ssrc from ingress. Dont send this one
-        // rtpbin.connect("on-new-ssrc", false, move |values| {
-        //     println!("ON NEW SSRC!!! {:?}", values);
-        //     if let [_, _, ssrc_value] = values {
-        //         if let Ok(ssrc) = ssrc_value.get::<u32>() {
-        //             println!("on new SSRC Value: {}", ssrc);
-        //         } else {
-        //             println!("Failed to extract SSRC value.");
-        //         }
-        //     }
-        //     None
-        // });
-        // rtpbin.connect("on-ssrc-sdes", false, |values| {
-        //     println!("ON SSRC SDES!!! {:?}", values);
-        //     None
-        // });
-
-        // // Some payload type changed?
-        // rtpbin.connect("payload-type-change", false, |values| {
-        //     println!("ON PAYLOAD CHANGE!!!! {:?}", values);
-        //     None
-        // });
-
-        //Set action to take when pad is added to rtpbin
-        // (connect this pad to a depayloader, parser, decoder, and then into the mixer)
-        let pipeline_weak = pipeline.downgrade(); //Downgrade to use in function
-        rtpbin.connect_pad_added(move |rtpbin, src_pad| {
-            println!("New source pad added to RTPBin");
-            println!("Creating new elements to handle new RTP stream");
-
-            let pipeline_strong = match pipeline_weak.upgrade() {
-                Some(pipeline) => pipeline,
-                None => return,
-            }; //Upgrade to use in function
-
-            //Make elements that will handle this new incoming stream
-            let rtpopusdepay = gstreamer::ElementFactory::make("rtpopusdepay")
-                .build()
-                .expect("Can not make RTP opus depayloader for new RTP media");
-            let opusparsein = gstreamer::ElementFactory::make("opusparse")
-                .build()
-                .expect("Can not make opus parser for new RTP media");
-            let opusdec = gstreamer::ElementFactory::make("opusdec")
-                .build()
-                .expect("Can not make opus decoder for new RTP media");
-            // Create the webrtcdsp element for echo cancellation
-            let webrtcdsp = gstreamer::ElementFactory::make("webrtcdsp")
-                .build()
-                .expect("Failed to create webrtcdsp");
-            let echo_probe = gstreamer::ElementFactory::make("webrtcechoprobe")
-                .build()
-                .expect("Failed to create webrtcechoprobe element");
-            webrtcdsp
-                .link(&echo_probe)
-                .expect("Failed to link webrtcdsp and echo probe");
-            // Add the webrtcdsp element to the pipeline
-            //Add elements to the pipeline
-            pipeline_strong
-                .add_many(&[
-                    &rtpopusdepay,
-                    &opusparsein,
-                    &opusdec,
-                    &webrtcdsp,
-                    &echo_probe,
-                ])
-                .expect("Can not add elements to pipeline!");
-
-            //Link the elements from the depayload to the output
-            let _ = gstreamer::Element::link_many(&[
-                &rtpopusdepay,
-                &opusparsein,
-                &opusdec,
-                // &webrtcdsp,
-                &audiomixer,
-                &opusenc,
-                &opusparseout,
-                &rtpopuspay,
-                &udpsink, //&oggmux,
-                          //&filesink
-            ]);
-            //rtpopuspay.set_property("pt", 100);
-
-            //Connect new rtpbin srcpad to the linked elements
-            // (this completes the pipe from the new media to the end output)
-            match connect_rtpbin_srcpad(src_pad, &rtpopusdepay) {
-                Ok(_) => (),
-                Err(err) => {
-                    element_error!(
-                        rtpbin,
-                        gstreamer::LibraryError::Failed,
-                        ("Failed to link srcpad"),
-                        ["{}", err]
-                    );
+        {
+            let mut mix = mix.lock().unwrap();
+            if mix_minus {
+                for (i, port) in input_ports.iter().enumerate() {
+                    let output = build_output(
+                        &pipeline,
+                        destination_ip,
+                        destination_port + i as u16,
+                        codec,
+                        &sent,
+                    )?;
+                    mix.outputs.insert(*port, output);
                 }
+            } else {
+                let output =
+                    build_output(&pipeline, destination_ip, destination_port, codec, &sent)?;
+                mix.outputs.insert(FULL_MIX, output);
             }
+        }
 
-            //This is important for elements not getting confused about time
-            rtpopusdepay
-                .sync_state_with_parent()
-                .expect("Can not sync element state with parent!");
-            opusparsein
-                .sync_state_with_parent()
-                .expect("Can not sync element state with parent!");
-            opusdec
-                .sync_state_with_parent()
-                .expect("Can not sync element state with parent!");
-            audiomixer
-                .sync_state_with_parent()
-                .expect("Can not sync element state with parent!");
-        });
+        // one receive branch per allocated port, feeding the outputs once it has a stream.
+        for port in input_ports {
+            add_input_branch(&pipeline, &mix, port, codec, session_id, &events).map_err(boxed)?;
+        }
 
         Ok(Self {
+            session_id: session_id.to_owned(),
+            events,
             pipeline: Arc::new(Mutex::new(Some(pipeline))),
-            input_ports,
+            codec: codec.clone(),
+            destination_ip: destination_ip.to_owned(),
+            mix_minus,
+            mix,
+            started_at: Mutex::new(None),
+            sent,
         })
     }
 
-    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("run");
+    // drive the pipeline from its bus until it ends, fails or is stopped. Messages
+    // are awaited rather than blocked on, so sessions share the tokio runtime.
+    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        debug!("session {} running", self.session_id);
         let pipeline = self.pipeline.lock().unwrap().as_ref().unwrap().clone();
 
         pipeline.set_state(gstreamer::State::Playing)?;
+        *self.started_at.lock().unwrap() = Some(Instant::now());
         let bus = pipeline
             .bus()
             .expect("Pipeline without bus. Shouldn't happen!");
 
         //Loop and move pipeline forward
-        for msg in bus.iter_timed(gstreamer::ClockTime::NONE) {
+        let mut started = false;
+        let mut messages = bus.stream();
+        while let Some(msg) = messages.next().await {
             match msg.view() {
-                MessageView::Eos(..) => break,
+                MessageView::Eos(..) => {
+                    // the eos went through the tracks too, the recording ends here.
+                    self.mix.lock().unwrap().stop_manifest();
+                    let _ = self.events.send(MixerEvent::Ended {
+                        session_id: self.session_id.clone(),
+                    });
+                    break;
+                }
+                MessageView::Application(app) => {
+                    if app.structure().map(|s| s.name() == STOP_MESSAGE) == Some(true) {
+                        debug!("session {} stop requested", self.session_id);
+                        self.mix.lock().unwrap().stop_manifest();
+                        break;
+                    }
+                }
                 MessageView::Error(err) => {
-                    pipeline
-                        .set_state(gstreamer::State::Null)
-                        .expect("Unable to set the pipeline to the `Null` state");
+                    set_null(&pipeline).await;
+                    self.mix.lock().unwrap().stop_manifest();
 
-                    return Err(ErrorMessage {
+                    let error = ErrorMessage {
                         src: msg
                             .src()
                             .map(|s| String::from(s.path_string()))
@@ -344,29 +489,38 @@ impl AudioMixerPipeline {
                         error: err.error().to_string(),
                         debug: err.debug().map(|gstr| gstr.as_str().to_owned()),
                         source: err.error(),
-                    }
-                    .into());
+                    };
+                    let _ = self.events.send(MixerEvent::Failed {
+                        session_id: self.session_id.clone(),
+                        error: error.to_string(),
+                    });
+                    return Err(error.into());
                 }
                 MessageView::StreamStart(s) => {
-                    println!("Received a StreamStart message: {:?}", s);
-                    // Additional handling of StreamStart messages can go here.
+                    debug!("session {} stream start: {:?}", self.session_id, s);
                 }
                 MessageView::Latency(s) => {
-                    println!("Received a Latency message: {:?}", s);
-                    // Additional handling of Latency messages can go here.
+                    trace!("session {} latency: {:?}", self.session_id, s);
                 }
                 MessageView::AsyncDone(s) => {
-                    println!("Received an AsyncDone message: {:?}", s);
-                    // Additional handling of AsyncDone messages can go here.
+                    debug!("session {} async done: {:?}", self.session_id, s);
                 }
                 MessageView::Element(el) => {
-                    println!("Received an Element message: {:?}", el);
-                    // Additional handling of element messages can go here.
+                    debug!("session {} element message: {:?}", self.session_id, el);
+                    if let (Some(src), Some(structure)) = (msg.src(), el.structure()) {
+                        self.recording_message(&pipeline, src, structure);
+                    }
                 }
                 MessageView::StateChanged(s) => {
                     if let Some(element) = msg.src() {
-                        if element.clone() == pipeline && s.current() == gstreamer::State::Playing {
-                            eprintln!("PLAYING");
+                        if element.clone() == pipeline
+                            && s.current() == gstreamer::State::Playing
+                            && !started
+                        {
+                            started = true;
+                            let _ = self.events.send(MixerEvent::Started {
+                                session_id: self.session_id.clone(),
+                            });
                             gstreamer::debug_bin_to_dot_file(
                                 &pipeline,
                                 gstreamer::DebugGraphDetails::all(),
@@ -375,48 +529,1012 @@ impl AudioMixerPipeline {
                         }
                     }
                 }
-                MessageView::Warning(s) => {
-                    println!("Warning: {:?} {:?}", s, msg.src())
+                MessageView::Warning(w) => {
+                    warn!(
+                        "session {} warning from {:?}: {} ({:?})",
+                        self.session_id,
+                        msg.src().map(|s| s.path_string()),
+                        w.error(),
+                        w.debug()
+                    );
                 }
-                MessageView::Info(s) => {
-                    println!("Warning: {:?} {:?}", s, msg.src())
+                MessageView::Info(i) => {
+                    debug!(
+                        "session {} info from {:?}: {} ({:?})",
+                        self.session_id,
+                        msg.src().map(|s| s.path_string()),
+                        i.error(),
+                        i.debug()
+                    );
                 }
                 MessageView::Tag(s) => {
-                    println!("Tag: {:?} {:?}", s, msg.src())
+                    trace!("session {} tag: {:?} {:?}", self.session_id, s, msg.src())
                 }
                 MessageView::StreamStatus(s) => {
-                    println!("Stream Status: {:?} and then {:?}", msg, s)
+                    trace!("session {} stream status: {:?}", self.session_id, s)
                 }
-
                 _ => {
-                    println!("Unknown {:?}", msg)
+                    trace!("session {} unhandled message: {:?}", self.session_id, msg)
                 }
             }
         }
 
         //Stop playing pipeline
+        set_null(&pipeline).await;
+
+        Ok(())
+    }
+
+    // attach a receive branch for a new producer on `port` while the mix keeps playing.
+    // In mix-minus the producer also gets a mix of everyone else sent to `destination_port`.
+    pub fn add_input(
+        &self,
+        port: u16,
+        destination_port: Option<u16>,
+    ) -> Result<Option<MixerOutput>, Box<dyn std::error::Error + Send + Sync>> {
+        let pipeline = self.pipeline.lock().unwrap().as_ref().unwrap().clone();
+        if self.mix.lock().unwrap().inputs.contains_key(&port) {
+            return Err(format!("Port {} is already an input", port).into());
+        }
+        let output = if self.mix_minus {
+            let destination_port = destination_port
+                .ok_or_else(|| format!("Input {} has no destination port", port))?;
+            let output = build_output(
+                &pipeline,
+                &self.destination_ip,
+                destination_port,
+                &self.codec,
+                &self.sent,
+            )?;
+            for element in output.elements.iter().rev() {
+                element.sync_state_with_parent()?;
+            }
+            let info = output.info(Some(port));
+            let mut mix = self.mix.lock().unwrap();
+            mix.outputs.insert(port, output);
+            // everyone already decoding is heard in the new participant's mix.
+            let sources: Vec<u16> = mix.inputs.keys().copied().collect();
+            for source in sources {
+                mix.link_feed(&pipeline, source, port).map_err(boxed)?;
+            }
+            Some(info)
+        } else {
+            None
+        };
+        add_input_branch(
+            &pipeline,
+            &self.mix,
+            port,
+            &self.codec,
+            &self.session_id,
+            &self.events,
+        )
+        .map_err(boxed)?;
+
+        let mix = self.mix.lock().unwrap();
+        let branch = &mix.inputs[&port];
+        branch.rtpbin.sync_state_with_parent()?;
+        branch.udpsrc.sync_state_with_parent()?;
+        Ok(output)
+    }
+
+    // detach the producer on `port`. Its source is stopped first so the branch is
+    // idle when it is unlinked, and the other inputs keep mixing undisturbed.
+    pub fn remove_input(&self, port: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let pipeline = self.pipeline.lock().unwrap().as_ref().unwrap().clone();
+        let branch = self
+            .mix
+            .lock()
+            .unwrap()
+            .inputs
+            .remove(&port)
+            .ok_or_else(|| format!("Port {} is not an input", port))?;
+
+        // rtpbin's threads take the mix lock in pad-added, so it's not held while they stop.
+        branch.udpsrc.set_state(gstreamer::State::Null)?;
+        branch.rtpbin.set_state(gstreamer::State::Null)?;
+        let mut mix = self.mix.lock().unwrap();
+        mix.positions.remove(&port);
+        let soloed = mix.controls.remove(&port).map(|controls| controls.solo) == Some(true);
+        for (output, feed) in branch.feeds {
+            if let (Some(tee), Some(output)) = (&branch.tee, mix.outputs.get(&output)) {
+                unlink_feed(&pipeline, tee, &output.mixer, feed)?;
+            }
+        }
+        if let (Some(tee), Some(feed), Some(recorder)) =
+            (&branch.tee, branch.recording, &mix.recorder)
+        {
+            unlink_feed(&pipeline, tee, &recorder.mixer, feed)?;
+        }
+        if let (Some(tee), Some(track)) = (&branch.tee, branch.track) {
+            mix.end_track(tee, track)?;
+        }
+        for element in &branch.decoder {
+            element.set_state(gstreamer::State::Null)?;
+        }
+        pipeline.remove_many(&[&branch.udpsrc, &branch.rtpbin])?;
+        for element in &branch.decoder {
+            pipeline.remove(element)?;
+        }
+
+        // the participant's own mix goes with them, and with it the others' feeds into it.
+        if let Some(output) = mix.outputs.remove(&port) {
+            for branch in mix.inputs.values_mut() {
+                if let (Some(tee), Some(feed)) = (&branch.tee, branch.feeds.remove(&port)) {
+                    unlink_feed(&pipeline, tee, &output.mixer, feed)?;
+                }
+            }
+            for element in &output.elements {
+                element.set_state(gstreamer::State::Null)?;
+                pipeline.remove(element)?;
+            }
+        }
+        // the others were silenced by the solo of the one leaving.
+        if soloed {
+            self.ramp(&mut mix);
+        }
+        Ok(())
+    }
+
+    // change the gain, mute or solo of the input on `port`, fading to the new levels.
+    pub fn set_controls(
+        &self,
+        port: u16,
+        gain_db: Option<f64>,
+        mute: Option<bool>,
+        solo: Option<bool>,
+    ) -> Result<MixerInput, Box<dyn std::error::Error + Send + Sync>> {
+        let mut mix = self.mix.lock().unwrap();
+        if !mix.inputs.contains_key(&port) {
+            return Err(format!("Port {} is not an input", port).into());
+        }
+        let controls = mix.controls.entry(port).or_default();
+        controls.gain_db = gain_db.unwrap_or(controls.gain_db);
+        controls.mute = mute.unwrap_or(controls.mute);
+        controls.solo = solo.unwrap_or(controls.solo);
+        let controls = controls.clone();
+        self.ramp(&mut mix);
+        Ok(MixerInput { port, controls })
+    }
+
+    // fade to the mix's current levels on a task of its own, so callers don't wait on it.
+    fn ramp(&self, mix: &mut Mix) {
+        let id = mix.start_ramp();
+        let shared = self.mix.clone();
+        tokio::spawn(async move {
+            let mut steps = tokio::time::interval(RAMP_STEP);
+            loop {
+                steps.tick().await;
+                if !shared.lock().unwrap().step_ramp(id) {
+                    break;
+                }
+            }
+        });
+    }
+
+    // move producers in a spatial session and re-place them in every mix. Ports
+    // that are no longer inputs are skipped, updates may race their removal.
+    pub fn update_positions(
+        &self,
+        positions: &[Position],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut mix = self.mix.lock().unwrap();
+        if mix.spatial.is_none() {
+            return Err("Session is not spatial".into());
+        }
+        for position in positions {
+            if mix.inputs.contains_key(&position.port) {
+                mix.positions.insert(position.port, position.clone());
+            }
+        }
+        mix.place();
+        Ok(())
+    }
+
+    // write a mix of every input to files named after `location`, see `RecordingInfo`,
+    // and with a manifest each input to `<prefix>-<port>-<n>` as well.
+    pub fn start_recording(
+        &self,
+        info: RecordingInfo,
+        prefix: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let pipeline = self.pipeline.lock().unwrap().as_ref().unwrap().clone();
+        let mut mix = self.mix.lock().unwrap();
+        if mix.recorder.is_some() {
+            return Err("Session is already recording".into());
+        }
+        let mut recorder = build_recorder(&pipeline, info, &self.codec)?;
+        recorder.prefix = prefix.to_owned();
+        recorder.manifest = recorder
+            .info
+            .manifest
+            .as_ref()
+            .map(|path| Manifest::new(path.into(), &self.session_id, &recorder.info.location));
+        for element in recorder.elements.iter().rev() {
+            element.sync_state_with_parent()?;
+        }
+        mix.recorder = Some(recorder);
+        let sources: Vec<u16> = mix.inputs.keys().copied().collect();
+        for source in sources {
+            mix.link_recording(&pipeline, source).map_err(boxed)?;
+            mix.link_track(&pipeline, source).map_err(boxed)?;
+        }
+        Ok(())
+    }
+
+    // cut the recorder off from the inputs and send it eos to close its file. It is
+    // removed from the pipeline once that's done, which the returned notify tells.
+    pub fn stop_recording(&self) -> Result<Arc<Notify>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut mix = self.mix.lock().unwrap();
+        if mix.recorder.is_none() {
+            return Err("Session is not recording".into());
+        }
+        let sources: Vec<u16> = mix.inputs.keys().copied().collect();
+        for source in sources {
+            let branch = mix.inputs.get_mut(&source).unwrap();
+            if let (Some(tee), Some(track)) = (branch.tee.clone(), branch.track.take()) {
+                mix.end_track(&tee, track)?;
+            }
+        }
+        let mut recorder = mix.recorder.take().unwrap();
+        if let Some(manifest) = &mut recorder.manifest {
+            manifest.stop();
+        }
+        for branch in mix.inputs.values_mut() {
+            if let (Some(tee), Some(feed)) = (&branch.tee, branch.recording.take()) {
+                let queue_sink = static_pad(&feed.elements[0], "sink")?;
+                feed.tee_pad.unlink(&queue_sink)?;
+                tee.release_request_pad(&feed.tee_pad);
+                queue_sink.send_event(gstreamer::event::Eos::new());
+                recorder.stopped_feeds.push(feed);
+            }
+        }
+        // nothing feeds the mixer, so the eos starts right after it.
+        if recorder.stopped_feeds.is_empty() {
+            static_pad(&recorder.mixer, "src")?.push_event(gstreamer::event::Eos::new());
+        }
+        let removed = recorder.removed.clone();
+        mix.stopped_recorders.push(recorder);
+        Ok(removed)
+    }
+
+    // stop the recording, if there is one, and hand out what tells when each recorder
+    // and track still closing its files is gone from the pipeline.
+    pub fn close_recordings(&self) -> Vec<Arc<Notify>> {
+        if self.recording().is_some() {
+            if let Err(e) = self.stop_recording() {
+                warn!(
+                    "session {} could not stop its recording: {}",
+                    self.session_id, e
+                );
+            }
+        }
+        let mix = self.mix.lock().unwrap();
+        mix.stopped_recorders
+            .iter()
+            .map(|recorder| recorder.removed.clone())
+            .chain(mix.stopped_tracks.iter().map(|track| track.removed.clone()))
+            .collect()
+    }
+
+    // end the manifests of recordings that won't close their files anymore, and hand
+    // out what finishes writing them.
+    pub fn close_manifests(&self) -> Vec<JoinHandle<()>> {
+        let mut mix = self.mix.lock().unwrap();
+        let mut recorders: Vec<Recorder> = mix.stopped_recorders.drain(..).collect();
+        recorders.extend(mix.recorder.take());
+        recorders
+            .into_iter()
+            .filter_map(|recorder| recorder.manifest)
+            .map(|mut manifest| {
+                manifest.stop();
+                manifest.close()
+            })
+            .collect()
+    }
+
+    pub fn recording(&self) -> Option<RecordingInfo> {
+        let mix = self.mix.lock().unwrap();
+        mix.recorder.as_ref().map(|recorder| recorder.info.clone())
+    }
+
+    // follow the files splitmuxsink opens and closes, reporting every closed one,
+    // and take a stopped recorder out of the pipeline once its eos got through.
+    fn recording_message(
+        &self,
+        pipeline: &Pipeline,
+        src: &gstreamer::Object,
+        structure: &gstreamer::StructureRef,
+    ) {
+        let mut mix = self.mix.lock().unwrap();
+        if structure.name() == "GstBinForwarded" {
+            let forwarded = match structure.get::<gstreamer::Message>("message") {
+                Ok(forwarded) => forwarded,
+                Err(_) => return,
+            };
+            if forwarded.type_() != gstreamer::MessageType::Eos {
+                return;
+            }
+            let stopped = mix.stopped_recorders.iter().position(|recorder| {
+                Some(recorder.sink.upcast_ref::<gstreamer::Object>()) == forwarded.src()
+            });
+            if let Some(i) = stopped {
+                let recorder = mix.stopped_recorders.remove(i);
+                drop(mix);
+                let (pipeline, session_id) = (pipeline.clone(), self.session_id.clone());
+                // like `set_null`, taking elements to Null waits for their streaming threads.
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = remove_recorder(&pipeline, recorder) {
+                        warn!(
+                            "session {} failed to remove its recorder: {}",
+                            session_id, e
+                        );
+                    }
+                });
+                return;
+            }
+            let stopped = mix.stopped_tracks.iter().position(|track| {
+                Some(
+                    track
+                        .elements
+                        .last()
+                        .unwrap()
+                        .upcast_ref::<gstreamer::Object>(),
+                ) == forwarded.src()
+            });
+            if let Some(i) = stopped {
+                let track = mix.stopped_tracks.remove(i);
+                drop(mix);
+                let size = std::fs::metadata(&track.path)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or_default();
+                let _ = self.events.send(MixerEvent::RecordingCompleted {
+                    session_id: self.session_id.clone(),
+                    path: track.path.clone(),
+                    duration: track.duration,
+                    size,
+                });
+                let (pipeline, session_id) = (pipeline.clone(), self.session_id.clone());
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = remove_track(&pipeline, &track) {
+                        warn!("session {} failed to remove a track: {}", session_id, e);
+                    }
+                });
+            }
+            return;
+        }
+        let recorder = match mix.recorder_mut(src) {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        let location = structure.get::<String>("location").unwrap_or_default();
+        let running_time = structure.get::<u64>("running-time").unwrap_or_default();
+        if structure.name() == "splitmuxsink-fragment-opened" {
+            recorder.file = Some((location, running_time));
+        } else if structure.name() == "splitmuxsink-fragment-closed" {
+            let opened_at = match recorder.file.take() {
+                Some((_, opened_at)) => opened_at,
+                None => running_time,
+            };
+            let size = std::fs::metadata(&location)
+                .map(|metadata| metadata.len())
+                .unwrap_or_default();
+            let _ = self.events.send(MixerEvent::RecordingCompleted {
+                session_id: self.session_id.clone(),
+                path: location,
+                duration: running_time.saturating_sub(opened_at) as f64 / 1e9,
+                size,
+            });
+        }
+    }
+
+    // ask a running pipeline to leave its bus loop; `run` then sets it to Null.
+    pub fn stop(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let pipeline = self.pipeline.lock().unwrap().as_ref().unwrap().clone();
+        let bus = pipeline
+            .bus()
+            .expect("Pipeline without bus. Shouldn't happen!");
+        let structure = gstreamer::Structure::new_empty(STOP_MESSAGE);
+        bus.post(gstreamer::message::Application::new(structure))?;
+        Ok(())
+    }
+
+    // send eos through the pipeline so the mix ends cleanly; `run` stops once it reaches the sink.
+    pub fn end(&self) {
+        let pipeline = self.pipeline.lock().unwrap().as_ref().unwrap().clone();
+        if !pipeline.send_event(gstreamer::event::Eos::new()) {
+            warn!("session {} did not accept eos", self.session_id);
+        }
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        let duration = self
+            .started_at
+            .lock()
+            .unwrap()
+            .map(|started_at| started_at.elapsed().as_secs_f64())
+            .unwrap_or_default();
+        SessionStats {
+            duration,
+            inputs: self.mix.lock().unwrap().inputs.len(),
+            packets_sent: self.sent.packets.load(Ordering::Relaxed),
+            bytes_sent: self.sent.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn get_input_ports(&self) -> Vec<u16> {
+        self.mix.lock().unwrap().inputs.keys().copied().collect()
+    }
+
+    pub fn inputs(&self) -> Vec<MixerInput> {
+        let mix = self.mix.lock().unwrap();
+        mix.inputs
+            .keys()
+            .map(|port| MixerInput {
+                port: *port,
+                controls: mix.controls(*port),
+            })
+            .collect()
+    }
+
+    pub fn outputs(&self) -> Vec<MixerOutput> {
+        self.mix
+            .lock()
+            .unwrap()
+            .outputs
+            .iter()
+            .map(|(key, output)| output.info(Some(*key).filter(|key| *key != FULL_MIX)))
+            .collect()
+    }
+}
+
+impl Output {
+    fn info(&self, input_port: Option<u16>) -> MixerOutput {
+        MixerOutput {
+            input_port,
+            destination_port: self.destination_port,
+            ssrc: self.ssrc,
+        }
+    }
+}
+
+// boxed, an anyhow error no longer downcasts to what it wraps; keep a missing
+// element recognizable for `MixerError`.
+fn boxed(e: Error) -> Box<dyn std::error::Error + Send + Sync> {
+    match e.downcast::<MissingElement>() {
+        Ok(missing) => Box::new(missing),
+        Err(e) => e.into(),
+    }
+}
+
+// a mixer added to a playing pipeline would otherwise start at running time 0 and
+// send the whole session so far as silence before any input.
+fn start_at_first_buffer(pipeline: &Pipeline, mixer: &Element) -> Result<(), Error> {
+    mixer.set_property_from_str("start-time-selection", "first");
+    // the first buffer out should be about where the pipeline is now.
+    let pipeline = pipeline.downgrade();
+    let name = mixer.name();
+    static_pad(mixer, "src")?.add_probe(PadProbeType::BUFFER, move |_pad, info| {
+        if let (Some(PadProbeData::Buffer(buffer)), Some(pipeline)) =
+            (&info.data, pipeline.upgrade())
+        {
+            if let (Some(pts), Some(now)) = (buffer.pts(), pipeline.current_running_time()) {
+                if now.saturating_sub(pts) > gstreamer::ClockTime::SECOND {
+                    warn!("{} started {} behind the pipeline", name, now - pts);
+                }
+            }
+        }
+        PadProbeReturn::Remove
+    });
+    Ok(())
+}
+
+// encode a new mixer's output and send it to `destination_port` under an ssrc of its own.
+fn build_output(
+    pipeline: &Pipeline,
+    destination_ip: &str,
+    destination_port: u16,
+    codec: &CodecPreferences,
+    sent: &Arc<SentCounters>,
+) -> Result<Output, Box<dyn std::error::Error + Send + Sync>> {
+    let audiomixer = ElementFactory::make("audiomixer")
+        .build()
+        .map_err(|_| MissingElement("audiomixer"))?;
+    let opusenc = ElementFactory::make("opusenc")
+        .build()
+        .map_err(|_| MissingElement("opusenc"))?;
+    let opusparseout = ElementFactory::make("opusparse")
+        .build()
+        .map_err(|_| MissingElement("opusparse"))?;
+    let rtpopuspay = ElementFactory::make("rtpopuspay")
+        .build()
+        .map_err(|_| MissingElement("rtpopuspay"))?;
+    let udpsink = ElementFactory::make("udpsink")
+        .build()
+        .map_err(|_| MissingElement("udpsink"))?;
+
+    let ssrc = rand::random::<u32>();
+    opusenc.set_property("bitrate", codec.bitrate);
+    rtpopuspay.set_property("pt", codec.payload_type as u32);
+    rtpopuspay.set_property("ssrc", ssrc);
+
+    udpsink.set_property("host", destination_ip);
+    udpsink.set_property("port", destination_port as i32);
+    let elements = [&audiomixer, &opusenc, &opusparseout, &rtpopuspay, &udpsink];
+    pipeline.add_many(&elements)?;
+    gstreamer::Element::link_many(&elements)?;
+    start_at_first_buffer(pipeline, &audiomixer)?;
+    info!(
+        "output ssrc {} to {}:{}",
+        ssrc, destination_ip, destination_port
+    );
+
+    // count what goes out to egress for the session statistics.
+    let sent = sent.clone();
+    static_pad(&rtpopuspay, "src")?.add_probe(PadProbeType::BUFFER, move |_pad, info| {
+        if let Some(PadProbeData::Buffer(ref buffer)) = &info.data {
+            sent.packets.fetch_add(1, Ordering::Relaxed);
+            sent.bytes
+                .fetch_add(buffer.size() as u64, Ordering::Relaxed);
+        }
+        PadProbeReturn::Ok
+    });
+
+    Ok(Output {
+        mixer: audiomixer.clone(),
+        elements: vec![audiomixer, opusenc, opusparseout, rtpopuspay, udpsink],
+        destination_port,
+        ssrc,
+    })
+}
+
+// branch `tee` into a new sink pad of `mixer` through a queue of its own.
+// with `pan`, through an audiopanorama so the feed can be placed left to right.
+fn link_feed(
+    pipeline: &Pipeline,
+    tee: &Element,
+    mixer: &Element,
+    pan: bool,
+) -> Result<Feed, Error> {
+    let queue = ElementFactory::make("queue")
+        .build()
+        .map_err(|_| MissingElement("queue"))?;
+    let panorama = if pan {
+        Some(
+            ElementFactory::make("audiopanorama")
+                .build()
+                .map_err(|_| MissingElement("audiopanorama"))?,
+        )
+    } else {
+        None
+    };
+    let elements: Vec<Element> = std::iter::once(queue).chain(panorama.clone()).collect();
+    let refs: Vec<&Element> = elements.iter().collect();
+    pipeline.add_many(&refs)?;
+    gstreamer::Element::link_many(&refs)?;
+    let tee_pad = tee
+        .request_pad_simple("src_%u")
+        .ok_or_else(|| NoSuchPad("src_%u", tee.name().to_string()))?;
+    let mixer_pad = mixer
+        .request_pad_simple("sink_%u")
+        .ok_or_else(|| NoSuchPad("sink_%u", mixer.name().to_string()))?;
+    static_pad(elements.last().unwrap(), "src")?.link(&mixer_pad)?;
+    tee_pad.link(&static_pad(&elements[0], "sink")?)?;
+    for element in elements.iter().rev() {
+        element.sync_state_with_parent()?;
+    }
+    Ok(Feed {
+        tee_pad,
+        elements,
+        panorama,
+        mixer_pad,
+    })
+}
+
+fn unlink_feed(
+    pipeline: &Pipeline,
+    tee: &Element,
+    mixer: &Element,
+    feed: Feed,
+) -> Result<(), Error> {
+    let queue_sink = static_pad(&feed.elements[0], "sink")?;
+    feed.tee_pad.unlink(&queue_sink)?;
+    tee.release_request_pad(&feed.tee_pad);
+    for element in &feed.elements {
+        element.set_state(gstreamer::State::Null)?;
+    }
+    if let Some(peer) = feed.mixer_pad.peer() {
+        peer.unlink(&feed.mixer_pad)?;
+    }
+    mixer.release_request_pad(&feed.mixer_pad);
+    let refs: Vec<&Element> = feed.elements.iter().collect();
+    pipeline.remove_many(&refs)?;
+    Ok(())
+}
+
+// mix every input into files named after `info.location`, a new one at either limit.
+fn build_recorder(
+    pipeline: &Pipeline,
+    info: RecordingInfo,
+    codec: &CodecPreferences,
+) -> Result<Recorder, Box<dyn std::error::Error + Send + Sync>> {
+    let audiomixer = ElementFactory::make("audiomixer")
+        .build()
+        .map_err(|_| MissingElement("audiomixer"))?;
+    let audioconvert = ElementFactory::make("audioconvert")
+        .build()
+        .map_err(|_| MissingElement("audioconvert"))?;
+    let encoder = match info.format {
+        RecordingFormat::Opus => {
+            let opusenc = ElementFactory::make("opusenc")
+                .build()
+                .map_err(|_| MissingElement("opusenc"))?;
+            opusenc.set_property("bitrate", codec.bitrate);
+            opusenc
+        }
+        RecordingFormat::Flac => ElementFactory::make("flacenc")
+            .build()
+            .map_err(|_| MissingElement("flacenc"))?,
+    };
+    let oggmux = ElementFactory::make("oggmux")
+        .build()
+        .map_err(|_| MissingElement("oggmux"))?;
+    let splitmuxsink = ElementFactory::make("splitmuxsink")
+        .build()
+        .map_err(|_| MissingElement("splitmuxsink"))?;
+
+    splitmuxsink.set_property("location", &info.location);
+    splitmuxsink.set_property("muxer", &oggmux);
+    if let Some(max_duration) = info.max_duration {
+        splitmuxsink.set_property("max-size-time", max_duration.saturating_mul(1_000_000_000));
+    }
+    if let Some(max_size) = info.max_size {
+        splitmuxsink.set_property("max-size-bytes", max_size);
+    }
+    let elements = [&audiomixer, &audioconvert, &encoder, &splitmuxsink];
+    pipeline.add_many(&elements)?;
+    gstreamer::Element::link_many(&elements)?;
+    start_at_first_buffer(pipeline, &audiomixer)?;
+    info!("recording to {}", info.location);
+
+    Ok(Recorder {
+        mixer: audiomixer.clone(),
+        elements: vec![audiomixer, audioconvert, encoder, splitmuxsink.clone()],
+        sink: splitmuxsink,
+        info,
+        file: None,
+        stopped_feeds: Vec::new(),
+        removed: Arc::new(Notify::new()),
+        prefix: String::new(),
+        manifest: None,
+    })
+}
+
+fn remove_track(pipeline: &Pipeline, track: &Track) -> Result<(), Error> {
+    for element in &track.elements {
+        element.set_state(gstreamer::State::Null)?;
+        pipeline.remove(element)?;
+    }
+    track.removed.notify_one();
+    Ok(())
+}
+
+// write the input decoded into `tee` to `path` on its own.
+fn build_track(
+    pipeline: &Pipeline,
+    tee: &Element,
+    format: RecordingFormat,
+    path: &str,
+) -> Result<Track, Error> {
+    let queue = ElementFactory::make("queue")
+        .build()
+        .map_err(|_| MissingElement("queue"))?;
+    let audioconvert = ElementFactory::make("audioconvert")
+        .build()
+        .map_err(|_| MissingElement("audioconvert"))?;
+    let encoder = match format {
+        RecordingFormat::Opus => ElementFactory::make("opusenc")
+            .build()
+            .map_err(|_| MissingElement("opusenc"))?,
+        RecordingFormat::Flac => ElementFactory::make("flacenc")
+            .build()
+            .map_err(|_| MissingElement("flacenc"))?,
+    };
+    let oggmux = ElementFactory::make("oggmux")
+        .build()
+        .map_err(|_| MissingElement("oggmux"))?;
+    let filesink = ElementFactory::make("filesink")
+        .build()
+        .map_err(|_| MissingElement("filesink"))?;
+    filesink.set_property("location", path);
+
+    let elements = vec![queue, audioconvert, encoder, oggmux, filesink];
+    let refs: Vec<&Element> = elements.iter().collect();
+    pipeline.add_many(&refs)?;
+    gstreamer::Element::link_many(&refs)?;
+    for element in elements.iter().rev() {
+        element.sync_state_with_parent()?;
+    }
+    let tee_pad = tee
+        .request_pad_simple("src_%u")
+        .ok_or_else(|| NoSuchPad("src_%u", tee.name().to_string()))?;
+    tee_pad.link(&static_pad(&elements[0], "sink")?)?;
+    info!("track to {}", path);
+    Ok(Track {
+        tee_pad,
+        elements,
+        path: path.to_owned(),
+        duration: 0.0,
+        removed: Arc::new(Notify::new()),
+    })
+}
+
+fn remove_recorder(pipeline: &Pipeline, recorder: Recorder) -> Result<(), Error> {
+    for feed in &recorder.stopped_feeds {
+        for element in &feed.elements {
+            element.set_state(gstreamer::State::Null)?;
+        }
+        recorder.mixer.release_request_pad(&feed.mixer_pad);
+        let refs: Vec<&Element> = feed.elements.iter().collect();
+        pipeline.remove_many(&refs)?;
+    }
+    for element in &recorder.elements {
+        element.set_state(gstreamer::State::Null)?;
+        pipeline.remove(element)?;
+    }
+    match recorder.manifest {
+        // its last update may still be on the way to disk.
+        Some(manifest) => {
+            let writer = manifest.close();
+            let removed = recorder.removed;
+            tokio::spawn(async move {
+                let _ = writer.await;
+                removed.notify_one();
+            });
+        }
+        None => recorder.removed.notify_one(),
+    }
+    Ok(())
+}
+
+// Shutting down waits for every streaming thread, do it off the async workers.
+async fn set_null(pipeline: &Pipeline) {
+    let pipeline = pipeline.clone();
+    let _ = tokio::task::spawn_blocking(move || {
         pipeline
             .set_state(gstreamer::State::Null)
             .expect("Unable to set the pipeline to the `Null` state");
+    })
+    .await;
+}
 
-        Ok(())
+// Build the udpsrc -> rtpbin half of a producer's branch on `port`. Once rtpbin
+// sees the stream it exposes a pad, and the depay -> parse -> decode half is made
+// there and fed into every output but the producer's own.
+fn add_input_branch(
+    pipeline: &Pipeline,
+    mix: &SharedMix,
+    port: u16,
+    codec: &CodecPreferences,
+    session_id: &str,
+    events: &EventSender,
+) -> Result<(), Error> {
+    let udpsrc = ElementFactory::make("udpsrc")
+        .name(format!("udpsrc_{}", port))
+        .build()
+        .map_err(|_| MissingElement("udpsrc"))?;
+    let rtpbin = ElementFactory::make("rtpbin")
+        .name(format!("rtpbin_{}", port))
+        .build()
+        .map_err(|_| MissingElement("rtpbin"))?;
+
+    let audio_caps = gstreamer::Caps::builder("application/x-rtp")
+        .field("media", "audio")
+        .field("clock-rate", codec.clock_rate)
+        .field("encoding-name", "OPUS")
+        .field("payload", codec.payload_type as i32)
+        .build();
+    udpsrc.set_property("port", port as i32);
+    udpsrc.set_property("caps", &audio_caps);
+
+    pipeline.add_many(&[&udpsrc, &rtpbin])?;
+    udpsrc.link(&rtpbin)?;
+
+    // Respond to determining payload type (audio, video)
+    let payload_type = codec.payload_type as u32;
+    let clock_rate = codec.clock_rate;
+    rtpbin.connect("request-pt-map", false, move |values| {
+        let pt = values[2]
+            .get::<u32>()
+            .expect("rtpbin \"new-storage\" signal values[2]");
+        debug!("rtpbin asks for caps of payload type {}", pt);
+        match pt {
+            pt if pt == payload_type => Some(
+                gstreamer::Caps::builder("application/x-rtp")
+                    .field("media", "audio")
+                    .field("clock-rate", clock_rate)
+                    .field("encoding-name", "OPUS")
+                    .build()
+                    .to_value(),
+            ),
+            _ => None,
+        }
+    });
+
+    // a producer starting and stopping to send shows up as rtp sources coming and going.
+    let session_id_clone = session_id.to_owned();
+    let events_clone = events.clone();
+    let mix_clone = mix.clone();
+    rtpbin.connect("on-new-ssrc", false, move |values| {
+        if let Ok(ssrc) = values[2].get::<u32>() {
+            mix_clone.lock().unwrap().source_event(port, ssrc, true);
+            let _ = events_clone.send(MixerEvent::InputJoined {
+                session_id: session_id_clone.clone(),
+                port,
+                ssrc,
+            });
+        }
+        None
+    });
+    for signal in ["on-timeout", "on-bye-ssrc"] {
+        let session_id_clone = session_id.to_owned();
+        let events_clone = events.clone();
+        let mix_clone = mix.clone();
+        rtpbin.connect(signal, false, move |values| {
+            if let Ok(ssrc) = values[2].get::<u32>() {
+                mix_clone.lock().unwrap().source_event(port, ssrc, false);
+                let _ = events_clone.send(MixerEvent::InputLost {
+                    session_id: session_id_clone.clone(),
+                    port,
+                    ssrc,
+                });
+            }
+            None
+        });
     }
 
-    pub fn get_input_ports(&self) -> Vec<u16> {
-        self.input_ports.clone()
+    //Set action to take when pad is added to rtpbin
+    // (connect this pad to a depayloader, parser, decoder, and then into the mixer)
+    let pipeline_weak = pipeline.downgrade(); //Downgrade to use in function
+    let mix_clone = mix.clone();
+    rtpbin.connect_pad_added(move |rtpbin, src_pad| {
+        if !src_pad.name().starts_with("recv_rtp_src_") {
+            return;
+        }
+        debug!(
+            "new source pad {} added to rtpbin on port {}",
+            src_pad.name(),
+            port
+        );
+
+        let pipeline_strong = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        }; //Upgrade to use in function
+
+        let mut mix = mix_clone.lock().unwrap();
+        let branch = match mix.inputs.get(&port) {
+            Some(branch) => branch,
+            None => return,
+        };
+        if branch.tee.is_some() {
+            warn!(
+                "Port {} already has a stream, ignoring {}",
+                port,
+                src_pad.name()
+            );
+            return;
+        }
+
+        let linked = link_decoder(&pipeline_strong, src_pad, payload_type).and_then(|decoder| {
+            let branch = mix.inputs.get_mut(&port).unwrap();
+            branch.tee = decoder.last().cloned();
+            branch.decoder = decoder;
+            // recv_rtp_src_<session>_<ssrc>_<pt>
+            branch.ssrc = src_pad
+                .name()
+                .split('_')
+                .nth(4)
+                .and_then(|ssrc| ssrc.parse().ok());
+            let listeners: Vec<u16> = mix
+                .outputs
+                .keys()
+                .copied()
+                .filter(|listener| *listener != port)
+                .collect();
+            for listener in listeners {
+                mix.link_feed(&pipeline_strong, port, listener)?;
+            }
+            mix.link_recording(&pipeline_strong, port)?;
+            mix.link_track(&pipeline_strong, port)
+        });
+        match linked {
+            Ok(()) => {}
+            Err(err) => {
+                element_error!(
+                    rtpbin,
+                    gstreamer::LibraryError::Failed,
+                    ("Failed to link srcpad"),
+                    ["{}", err]
+                );
+            }
+        }
+    });
+
+    mix.lock().unwrap().inputs.insert(
+        port,
+        InputBranch {
+            udpsrc,
+            rtpbin,
+            decoder: Vec::new(),
+            tee: None,
+            feeds: BTreeMap::new(),
+            recording: None,
+            ssrc: None,
+            track: None,
+        },
+    );
+    Ok(())
+}
+
+// depay -> parse -> decode -> convert -> tee a new rtpbin stream; the tee is last.
+fn link_decoder(
+    pipeline: &Pipeline,
+    src_pad: &Pad,
+    payload_type: u32,
+) -> Result<Vec<Element>, Error> {
+    //Make elements that will handle this new incoming stream
+    let rtpopusdepay = ElementFactory::make("rtpopusdepay")
+        .build()
+        .map_err(|_| MissingElement("rtpopusdepay"))?;
+    let opusparsein = ElementFactory::make("opusparse")
+        .build()
+        .map_err(|_| MissingElement("opusparse"))?;
+    let opusdec = ElementFactory::make("opusdec")
+        .build()
+        .map_err(|_| MissingElement("opusdec"))?;
+    let audioconvert = ElementFactory::make("audioconvert")
+        .build()
+        .map_err(|_| MissingElement("audioconvert"))?;
+
+    let tee = ElementFactory::make("tee")
+        .build()
+        .map_err(|_| MissingElement("tee"))?;
+    // keep decoding while no output is fed yet, e.g. a mix-minus session of one.
+    tee.set_property("allow-not-linked", true);
+
+    //Add elements to the pipeline and link them from the depayload to the tee
+    let elements = [&rtpopusdepay, &opusparsein, &opusdec, &audioconvert, &tee];
+    pipeline.add_many(&elements)?;
+    gstreamer::Element::link_many(&elements)?;
+
+    //Connect new rtpbin srcpad to the linked elements
+    // (this completes the pipe from the new media to the end output)
+    connect_rtpbin_srcpad(src_pad, &rtpopusdepay, payload_type)?;
+
+    let decoder = vec![rtpopusdepay, opusparsein, opusdec, audioconvert, tee];
+
+    //This is important for elements not getting confused about time
+    for element in &decoder {
+        element.sync_state_with_parent()?;
     }
+    Ok(decoder)
 }
 
 // Connect source pad to rtpbin
-fn connect_rtpbin_srcpad(src_pad: &gstreamer::Pad, sink: &gstreamer::Element) -> Result<(), Error> {
+fn connect_rtpbin_srcpad(
+    src_pad: &gstreamer::Pad,
+    sink: &gstreamer::Element,
+    payload_type: u32,
+) -> Result<(), Error> {
     let name = src_pad.name();
     let split_name = name.split('_');
     let split_name = split_name.collect::<Vec<&str>>();
     let pt = split_name[5].parse::<u32>()?;
 
     match pt {
-        100 => {
-            println!("Payload type is 100 YAY!");
+        pt if pt == payload_type => {
+            debug!("linking payload type {}", pt);
             let sinkpad = static_pad(sink, "sink");
             let _ = src_pad.link(&sinkpad.unwrap());
             Ok(())
@@ -438,3 +1556,78 @@ fn static_pad(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::ErrorCode;
+    use crate::mixer::MixerError;
+
+    fn controls(mix: &mut Mix, port: u16, gain_db: f64, mute: bool, solo: bool) {
+        mix.controls.insert(
+            port,
+            InputControls {
+                gain_db,
+                mute,
+                solo,
+            },
+        );
+    }
+
+    #[test]
+    fn mute_wins_over_solo() {
+        let mut mix = Mix::default();
+        controls(&mut mix, 5000, 0.0, true, true);
+        assert!(!mix.audible(5000));
+        assert_eq!(mix.level(5000, None), (0.0, 0.0));
+    }
+
+    #[test]
+    fn solo_silences_the_others() {
+        let mut mix = Mix::default();
+        controls(&mut mix, 5000, 0.0, false, true);
+        assert!(mix.audible(5000));
+        assert!(!mix.audible(5002));
+        assert_eq!(mix.level(5002, None), (0.0, 0.0));
+
+        // a second solo is heard alongside the first.
+        controls(&mut mix, 5002, 0.0, false, true);
+        assert!(mix.audible(5002));
+        assert!(!mix.audible(5004));
+    }
+
+    #[test]
+    fn everyone_is_heard_once_the_last_solo_ends() {
+        let mut mix = Mix::default();
+        controls(&mut mix, 5000, 0.0, false, true);
+        controls(&mut mix, 5002, 0.0, false, true);
+        controls(&mut mix, 5000, 0.0, false, false);
+        assert!(!mix.audible(5004));
+        mix.controls.remove(&5002);
+        assert!(mix.audible(5000));
+        assert!(mix.audible(5002));
+        assert!(mix.audible(5004));
+    }
+
+    #[test]
+    fn gain_applies_to_audible_inputs() {
+        let mut mix = Mix::default();
+        controls(&mut mix, 5000, -20.0, false, false);
+        assert_eq!(mix.level(5004, None), (1.0, 0.0));
+        let (volume, _) = mix.level(5000, None);
+        assert!((volume - 0.1).abs() < 1e-9);
+
+        controls(&mut mix, 5002, 6.0, false, true);
+        assert_eq!(mix.level(5000, None), (0.0, 0.0));
+        let (volume, _) = mix.level(5002, None);
+        assert!((volume - 1.995).abs() < 1e-3);
+    }
+
+    #[test]
+    fn missing_element_survives_anyhow() {
+        let missing = boxed(Error::from(MissingElement("udpsrc")));
+        assert_eq!(MixerError::from(missing).code, ErrorCode::MissingElement);
+        let other = boxed(Error::from(NoSuchPad("src_%u", "tee0".to_owned())));
+        assert_eq!(MixerError::from(other).code, ErrorCode::PipelineFailure);
+    }
+}
@@ -1,7 +1,8 @@
+use super::MixerError;
+use crate::message::ErrorCode;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use std::collections::HashSet;
-use std::error::Error;
 
 pub struct PortRangeManager {
     available_ports: Vec<u16>,
@@ -16,12 +17,12 @@ impl PortRangeManager {
         }
     }
 
-    pub fn allocate_ports(&mut self, count: usize) -> Result<Vec<u16>, Box<dyn Error>> {
+    pub fn allocate_ports(&mut self, count: usize) -> Result<Vec<u16>, MixerError> {
         if self.available_ports.len() < count {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(MixerError::new(
+                ErrorCode::PortExhausted,
                 "Not enough available ports",
-            )));
+            ));
         }
 
         let mut rng = thread_rng();
@@ -34,6 +35,14 @@ impl PortRangeManager {
         Ok(allocated_ports)
     }
 
+    pub fn available(&self) -> usize {
+        self.available_ports.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.available_ports.len() + self.used_ports.len()
+    }
+
     pub fn deallocate_ports(&mut self, ports: &[u16]) {
         for port in ports {
             if self.used_ports.remove(port) {
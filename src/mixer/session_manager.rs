@@ -1,82 +1,561 @@
-use uuid::serde;
-
 use super::port_range_manager::PortRangeManager;
+use super::{EventSender, MixerError};
+use crate::message::{
+    CodecPreferences, ErrorCode, MixerInput, MixerOutput, Position, RecordingFormat, RecordingInfo,
+    SessionInfo, SessionStats, SpatialSettings,
+};
 use crate::mixer::pipeline::AudioMixerPipeline;
+use futures::future;
 use std::collections::HashMap;
-use std::error::Error;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+// a session registered with the manager and the task driving its pipeline.
+struct MixerSession {
+    pipeline: Arc<AudioMixerPipeline>,
+    destination_ip: String,
+    destination_port: u16,
+    codec: CodecPreferences,
+    mix_minus: bool,
+    spatial: Option<SpatialSettings>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MixerSession {
+    fn info(&self, session_id: &str) -> SessionInfo {
+        SessionInfo {
+            session_id: session_id.to_owned(),
+            port_range: self.pipeline.get_input_ports(),
+            destination_ip: self.destination_ip.clone(),
+            destination_port: self.destination_port,
+            codec: self.codec.clone(),
+            mix_minus: self.mix_minus,
+            spatial: self.spatial.clone(),
+            inputs: self.pipeline.inputs(),
+            outputs: self.pipeline.outputs(),
+            recording: self.pipeline.recording(),
+            stats: self.pipeline.stats(),
+        }
+    }
+}
+
+// how long a session being destroyed waits for its recording to close the last file.
+const RECORDING_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
+// one per node, shared by every connection to the api server.
 pub struct MixerSessionManager {
-    sessions: Arc<Mutex<HashMap<String, AudioMixerPipeline>>>,
+    events: EventSender,
+    sessions: Arc<Mutex<HashMap<String, MixerSession>>>,
     port_range_manager: Mutex<PortRangeManager>,
+    draining: AtomicBool,
 }
 
 impl MixerSessionManager {
-    pub fn new(port_range: (u16, u16)) -> Self {
+    pub fn new(port_range: (u16, u16), events: EventSender) -> Self {
         MixerSessionManager {
+            events,
             sessions: Arc::new(Mutex::new(HashMap::new())),
             port_range_manager: Mutex::new(PortRangeManager::new(port_range.0, port_range.1)),
+            draining: AtomicBool::new(false),
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_session(
         &self,
         session_id: String,
         num_input_ports: usize,
         destination_ip: &str,
         destination_port: u16,
-    ) -> Result<Vec<u16>, Box<dyn Error>> {
+        codec: &CodecPreferences,
+        mix_minus: bool,
+        spatial: Option<SpatialSettings>,
+    ) -> Result<Vec<u16>, MixerError> {
+        self.check_draining()?;
+        if session_id.is_empty() {
+            return Err(bad_parameters("sessionId must not be empty"));
+        }
+        if destination_ip.parse::<IpAddr>().is_err() {
+            return Err(bad_parameters(format!(
+                "destinationIp {:?} is not an ip address",
+                destination_ip
+            )));
+        }
+        if destination_port == 0 {
+            return Err(bad_parameters("destinationPort must not be 0"));
+        }
+        // compared this way round so a huge producer count can't overflow.
+        if mix_minus && num_input_ports > u16::MAX as usize + 1 - destination_port as usize {
+            return Err(bad_parameters(
+                "destinationPort leaves no room for a port per producer",
+            ));
+        }
+        codec.validate().map_err(bad_parameters)?;
+        if let Some(settings) = &spatial {
+            // each listener needs a mix of its own to be placed in.
+            if !mix_minus {
+                return Err(bad_parameters("spatial sessions must be mix-minus"));
+            }
+            settings.validate().map_err(bad_parameters)?;
+            // only made once producers arrive, so check for it before accepting the session.
+            if gstreamer::ElementFactory::find("audiopanorama").is_none() {
+                return Err(MixerError::new(
+                    ErrorCode::MissingElement,
+                    "Missing element audiopanorama",
+                ));
+            }
+        }
+
+        // hold the session map for the whole creation so the same id can't race in twice.
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.contains_key(&session_id) {
+            return Err(MixerError::new(
+                ErrorCode::SessionExists,
+                format!("Session {} already exists", session_id),
+            ));
+        }
         let input_ports = {
             let mut port_manager = self.port_range_manager.lock().unwrap();
             port_manager.allocate_ports(num_input_ports)?
         };
-        println!("after inputports");
-        println!(
-            "inpurt_ports: {:?}, destination_ip: {:?}, destination_port: {:?}",
+        debug!(
+            "session {} input ports: {:?}, destination: {}:{}, codec: {:?}",
+            session_id, input_ports, destination_ip, destination_port, codec
+        );
+        let audio_mixer_pipeline = match AudioMixerPipeline::new(
+            &session_id,
+            self.events.clone(),
             input_ports.clone(),
-            destination_ip.clone(),
-            destination_port.clone()
+            destination_ip,
+            destination_port,
+            codec,
+            mix_minus,
+            spatial.clone(),
+        ) {
+            Ok(audio_mixer_pipeline) => audio_mixer_pipeline,
+            Err(e) => {
+                self.port_range_manager
+                    .lock()
+                    .unwrap()
+                    .deallocate_ports(&input_ports);
+                return Err(e.into());
+            }
+        };
+        debug!("session {} pipeline built", session_id);
+        sessions.insert(
+            session_id,
+            MixerSession {
+                pipeline: Arc::new(audio_mixer_pipeline),
+                destination_ip: destination_ip.to_owned(),
+                destination_port,
+                codec: codec.clone(),
+                mix_minus,
+                spatial,
+                handle: None,
+            },
         );
-        let audio_mixer_pipeline =
-            AudioMixerPipeline::new(input_ports.clone(), destination_ip, destination_port)?;
-        println!("after audio_mixer_pipeline");
-        self.sessions
+        Ok(input_ports)
+    }
+
+    // run the session's pipeline as a task on the current runtime until it is stopped.
+    // the session map is only locked to register the task, not for the session's lifetime.
+    pub fn start_session(&self, session_id: &str) -> Result<(), MixerError> {
+        debug!("starting session {}", session_id);
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .get_mut(session_id)
+            .ok_or_else(|| not_found(session_id))?;
+        let audio_mixer_pipeline = session.pipeline.clone();
+        let session_id = session_id.to_owned();
+        session.handle = Some(tokio::spawn(async move {
+            if let Err(e) = audio_mixer_pipeline.run().await {
+                warn!("session {} failed: {}", session_id, e);
+            }
+        }));
+        Ok(())
+    }
+
+    // stop the pipeline, wait for its task to bring it to Null and hand the ports back.
+    pub async fn destroy_session(&self, session_id: &str) -> Result<SessionStats, MixerError> {
+        let (audio_mixer_pipeline, handle) = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = sessions
+                .get_mut(session_id)
+                .ok_or_else(|| not_found(session_id))?;
+            (session.pipeline.clone(), session.handle.take())
+        };
+        let playing = matches!(&handle, Some(handle) if !handle.is_finished());
+        close_recordings(session_id, &audio_mixer_pipeline, playing).await;
+        // a pipeline that already failed or ended is in Null, its bus takes no more
+        // messages and there's nothing left to stop.
+        if let Some(handle) = &handle {
+            if !handle.is_finished() {
+                if let Err(e) = audio_mixer_pipeline.stop() {
+                    warn!("session {} could not be stopped: {}", session_id, e);
+                    handle.abort();
+                }
+            }
+        }
+        if let Some(handle) = handle {
+            if matches!(handle.await, Err(e) if e.is_panic()) {
+                error!("session {} task panicked", session_id);
+            }
+        }
+        self.remove_session(session_id)
+    }
+
+    // allocate a port for a new producer and attach it to a running session.
+    // A mix-minus session also starts sending the producer its own mix.
+    pub fn add_input(
+        &self,
+        session_id: &str,
+        destination_port: Option<u16>,
+    ) -> Result<(u16, Option<MixerOutput>), MixerError> {
+        self.check_draining()?;
+        let (audio_mixer_pipeline, destination_port) = {
+            let sessions = self.sessions.lock().unwrap();
+            let session = sessions
+                .get(session_id)
+                .ok_or_else(|| not_found(session_id))?;
+            let destination_port = match (session.mix_minus, destination_port) {
+                (false, Some(_)) => {
+                    return Err(bad_parameters(
+                        "destinationPort is only taken by mix-minus sessions",
+                    ))
+                }
+                (false, None) => None,
+                (true, Some(0)) => return Err(bad_parameters("destinationPort must not be 0")),
+                (true, Some(port)) => {
+                    let taken = session
+                        .pipeline
+                        .outputs()
+                        .iter()
+                        .any(|output| output.destination_port == port);
+                    if taken {
+                        return Err(bad_parameters(format!(
+                            "destinationPort {} already receives a mix",
+                            port
+                        )));
+                    }
+                    Some(port)
+                }
+                (true, None) => Some(next_destination_port(session)?),
+            };
+            (session.pipeline.clone(), destination_port)
+        };
+        let port = {
+            let mut port_manager = self.port_range_manager.lock().unwrap();
+            port_manager.allocate_ports(1)?[0]
+        };
+        match audio_mixer_pipeline.add_input(port, destination_port) {
+            Ok(output) => Ok((port, output)),
+            Err(e) => {
+                self.port_range_manager
+                    .lock()
+                    .unwrap()
+                    .deallocate_ports(&[port]);
+                Err(e.into())
+            }
+        }
+    }
+
+    // detach the producer on `port` from a session and hand the port back.
+    pub fn remove_input(&self, session_id: &str, port: u16) -> Result<(), MixerError> {
+        let audio_mixer_pipeline = self.session_pipeline(session_id)?;
+        audio_mixer_pipeline.remove_input(port)?;
+        self.port_range_manager
             .lock()
             .unwrap()
-            .insert(session_id.clone(), audio_mixer_pipeline);
-        println!("before ok");
-        Ok(input_ports)
+            .deallocate_ports(&[port]);
+        Ok(())
     }
 
-    pub async fn start_session(&self, session_id: &str) -> Result<(), Box<dyn Error>> {
-        println!("start session");
-        let sessions = self.sessions.lock().unwrap();
-        if let Some(audio_mixer_pipeline) = sessions.get(session_id) {
-            println!("some audio");
-            audio_mixer_pipeline.run().await?;
-            println!("start_session");
-            Ok(())
-        } else {
-            Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                format!("Session {} not found", session_id),
-            )))
+    // change how the producer on `port` is heard, see `AudioMixerPipeline::set_controls`.
+    pub fn set_input(
+        &self,
+        session_id: &str,
+        port: u16,
+        gain_db: Option<f64>,
+        mute: Option<bool>,
+        solo: Option<bool>,
+    ) -> Result<MixerInput, MixerError> {
+        // the mixer pad's volume goes up to 10, +20 dB.
+        if let Some(gain_db) = gain_db {
+            if !gain_db.is_finite() || gain_db > 20.0 {
+                return Err(bad_parameters("gainDb must be a number up to 20"));
+            }
+        }
+        let audio_mixer_pipeline = self.session_pipeline(session_id)?;
+        if !audio_mixer_pipeline.get_input_ports().contains(&port) {
+            return Err(bad_parameters(format!(
+                "Port {} is not an input of session {}",
+                port, session_id
+            )));
+        }
+        Ok(audio_mixer_pipeline.set_controls(port, gain_db, mute, solo)?)
+    }
+
+    // record the session into `dir`, in files named after the session and the time
+    // the recording started. multitrack adds a file per producer and a manifest.
+    pub fn start_recording(
+        &self,
+        session_id: &str,
+        dir: &str,
+        format: RecordingFormat,
+        max_duration: Option<u64>,
+        max_size: Option<u64>,
+        multitrack: bool,
+    ) -> Result<RecordingInfo, MixerError> {
+        self.check_draining()?;
+        if max_duration == Some(0) || max_size == Some(0) {
+            return Err(bad_parameters("maxDuration and maxSize must not be 0"));
+        }
+        let audio_mixer_pipeline = self.session_pipeline(session_id)?;
+        if audio_mixer_pipeline.recording().is_some() {
+            return Err(bad_parameters(format!(
+                "Session {} is already recording",
+                session_id
+            )));
+        }
+        std::fs::create_dir_all(dir).map_err(|e| {
+            MixerError::new(
+                ErrorCode::PipelineFailure,
+                format!("can not create {}: {}", dir, e),
+            )
+        })?;
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        // session ids come from the api server, keep them to one path component.
+        let name: String = session_id
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        let prefix = Path::new(dir)
+            .join(format!("{}-{}", name, started))
+            .to_string_lossy()
+            .into_owned();
+        let info = RecordingInfo {
+            format,
+            location: format!("{}-%03d.{}", prefix, format.extension()),
+            max_duration,
+            max_size,
+            manifest: multitrack.then(|| format!("{}.json", prefix)),
+        };
+        audio_mixer_pipeline.start_recording(info.clone(), &prefix)?;
+        Ok(info)
+    }
+
+    pub fn stop_recording(&self, session_id: &str) -> Result<(), MixerError> {
+        let audio_mixer_pipeline = self.session_pipeline(session_id)?;
+        if audio_mixer_pipeline.recording().is_none() {
+            return Err(bad_parameters(format!(
+                "Session {} is not recording",
+                session_id
+            )));
         }
+        audio_mixer_pipeline.stop_recording()?;
+        Ok(())
+    }
+
+    // move producers of a spatial session, see `AudioMixerPipeline::update_positions`.
+    pub fn update_positions(
+        &self,
+        session_id: &str,
+        positions: &[Position],
+    ) -> Result<(), MixerError> {
+        let audio_mixer_pipeline = {
+            let sessions = self.sessions.lock().unwrap();
+            let session = sessions
+                .get(session_id)
+                .ok_or_else(|| not_found(session_id))?;
+            if session.spatial.is_none() {
+                return Err(bad_parameters(format!(
+                    "Session {} is not spatial",
+                    session_id
+                )));
+            }
+            session.pipeline.clone()
+        };
+        audio_mixer_pipeline.update_positions(positions)?;
+        Ok(())
     }
 
-    pub fn remove_session(&self, session_id: &str) -> Result<(), Box<dyn Error>> {
+    pub fn remove_session(&self, session_id: &str) -> Result<SessionStats, MixerError> {
         let removed_session = self.sessions.lock().unwrap().remove(session_id);
 
-        if let Some(audio_mixer_pipeline) = removed_session {
-            let input_ports = audio_mixer_pipeline.get_input_ports();
+        if let Some(session) = removed_session {
+            let input_ports = session.pipeline.get_input_ports();
             let mut port_manager = self.port_range_manager.lock().unwrap();
             port_manager.deallocate_ports(&input_ports);
-            Ok(())
+            Ok(session.pipeline.stats())
         } else {
-            Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                format!("Session {} not found", session_id),
-            )))
+            Err(not_found(session_id))
         }
     }
+
+    pub fn get_session(&self, session_id: &str) -> Result<SessionInfo, MixerError> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .get(session_id)
+            .ok_or_else(|| not_found(session_id))?;
+        Ok(session.info(session_id))
+    }
+
+    pub fn list_sessions(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .iter()
+            .map(|(session_id, session)| session.info(session_id))
+            .collect()
+    }
+
+    // number of sessions and of inputs mixed across all of them.
+    pub fn session_counts(&self) -> (usize, usize) {
+        let sessions = self.sessions.lock().unwrap();
+        let inputs = sessions
+            .values()
+            .map(|session| session.pipeline.get_input_ports().len())
+            .sum();
+        (sessions.len(), inputs)
+    }
+
+    // ports still free to hand out and the size of the whole range.
+    pub fn port_capacity(&self) -> (usize, usize) {
+        let port_manager = self.port_range_manager.lock().unwrap();
+        (port_manager.available(), port_manager.capacity())
+    }
+
+    // refuse new sessions and inputs from now on; running sessions are left alone.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    // end every session with eos and give them `timeout` to flush it out. Sessions
+    // still running after that are stopped outright. Either way `run` leaves the
+    // pipeline in Null before its task finishes.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.drain();
+        let mut running: Vec<(String, Arc<AudioMixerPipeline>, JoinHandle<()>)> = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions
+                .iter_mut()
+                .filter_map(|(session_id, session)| {
+                    let handle = session.handle.take()?;
+                    Some((session_id.clone(), session.pipeline.clone(), handle))
+                })
+                .collect()
+        };
+        info!("ending {} sessions", running.len());
+        // the node exits right after, so every file and manifest is closed first.
+        future::join_all(
+            running
+                .iter()
+                .map(|(session_id, audio_mixer_pipeline, handle)| {
+                    close_recordings(session_id, audio_mixer_pipeline, !handle.is_finished())
+                }),
+        )
+        .await;
+        for (_, audio_mixer_pipeline, _) in &running {
+            audio_mixer_pipeline.end();
+        }
+        let ended = tokio::time::timeout(timeout, async {
+            for (_, _, handle) in running.iter_mut() {
+                let _ = handle.await;
+            }
+        })
+        .await;
+        if ended.is_err() {
+            for (session_id, audio_mixer_pipeline, handle) in running.iter_mut() {
+                if handle.is_finished() {
+                    continue;
+                }
+                warn!("session {} did not end in time, stopping it", session_id);
+                let _ = audio_mixer_pipeline.stop();
+                let _ = handle.await;
+            }
+        }
+        let session_ids: Vec<String> = self.sessions.lock().unwrap().keys().cloned().collect();
+        for session_id in session_ids {
+            let _ = self.remove_session(&session_id);
+        }
+    }
+
+    fn check_draining(&self) -> Result<(), MixerError> {
+        if self.is_draining() {
+            return Err(MixerError::new(
+                ErrorCode::Draining,
+                "Node is shutting down",
+            ));
+        }
+        Ok(())
+    }
+
+    fn session_pipeline(&self, session_id: &str) -> Result<Arc<AudioMixerPipeline>, MixerError> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .get(session_id)
+            .ok_or_else(|| not_found(session_id))?;
+        Ok(session.pipeline.clone())
+    }
+}
+
+// the lowest port from the session's destination port that no output is sent to yet.
+// let the session's recording close its files while the pipeline still plays, then
+// wait for what its manifests have left to write.
+async fn close_recordings(
+    session_id: &str,
+    audio_mixer_pipeline: &AudioMixerPipeline,
+    playing: bool,
+) {
+    // only a playing pipeline gets the eos through that closes the files.
+    if playing {
+        let closing = audio_mixer_pipeline.close_recordings();
+        let closed = future::join_all(closing.iter().map(|removed| removed.notified()));
+        if tokio::time::timeout(RECORDING_CLOSE_TIMEOUT, closed)
+            .await
+            .is_err()
+        {
+            warn!("session {} recording did not close in time", session_id);
+        }
+    }
+    for writer in audio_mixer_pipeline.close_manifests() {
+        let _ = writer.await;
+    }
+}
+
+fn next_destination_port(session: &MixerSession) -> Result<u16, MixerError> {
+    let used: Vec<u16> = session
+        .pipeline
+        .outputs()
+        .iter()
+        .map(|output| output.destination_port)
+        .collect();
+    (session.destination_port..=u16::MAX)
+        .find(|port| !used.contains(port))
+        .ok_or_else(|| bad_parameters("no destination port left for the new producer"))
+}
+
+fn not_found(session_id: &str) -> MixerError {
+    MixerError::new(
+        ErrorCode::SessionNotFound,
+        format!("Session {} not found", session_id),
+    )
+}
+
+fn bad_parameters(message: impl Into<String>) -> MixerError {
+    MixerError::new(ErrorCode::BadParameters, message)
 }
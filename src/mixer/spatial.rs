@@ -0,0 +1,106 @@
+use crate::message::{Position, SpatialSettings};
+
+// volume and stereo pan of `source` in the mix heard by `listener`. Without both
+// positions there's nothing to place, so the source is heard as is.
+pub fn placement(
+    settings: &SpatialSettings,
+    listener: Option<&Position>,
+    source: Option<&Position>,
+) -> (f64, f32) {
+    let (listener, source) = match (listener, source) {
+        (Some(listener), Some(source)) => (listener, source),
+        _ => return (1.0, 0.0),
+    };
+    let (dx, dy, dz) = (
+        source.x - listener.x,
+        source.y - listener.y,
+        source.z - listener.z,
+    );
+    let distance = (dx * dx + dy * dy + dz * dz).sqrt();
+    if distance >= settings.max_distance {
+        return (0.0, 0.0);
+    }
+
+    // inverse distance, clamped to full volume inside ref_distance.
+    let clamped = distance.max(settings.ref_distance);
+    let volume = settings.ref_distance
+        / (settings.ref_distance + settings.rolloff * (clamped - settings.ref_distance));
+
+    // how far to the listener's right the source is, -1 hard left to 1 hard right.
+    // A source above or below the listener is heard closer to the middle.
+    let pan = if distance > f64::EPSILON {
+        let (right_x, right_z) = (listener.yaw.cos(), -listener.yaw.sin());
+        ((dx * right_x + dz * right_z) / distance).clamp(-1.0, 1.0)
+    } else {
+        0.0
+    };
+    (volume, pan as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    fn at(x: f64, y: f64, z: f64, yaw: f64) -> Position {
+        Position {
+            port: 0,
+            x,
+            y,
+            z,
+            yaw,
+        }
+    }
+
+    // ref distance 1, max distance 50, rolloff 1.
+    fn place(listener: Position, source: Position) -> (f64, f32) {
+        placement(&SpatialSettings::default(), Some(&listener), Some(&source))
+    }
+
+    #[test]
+    fn full_volume_within_ref_distance() {
+        let (volume, _) = place(at(0.0, 0.0, 0.0, 0.0), at(0.5, 0.0, 0.0, 0.0));
+        assert_eq!(volume, 1.0);
+        let (volume, _) = place(at(0.0, 0.0, 0.0, 0.0), at(4.0, 0.0, 0.0, 0.0));
+        assert!((volume - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn silent_beyond_max_distance() {
+        let (volume, pan) = place(at(0.0, 0.0, 0.0, 0.0), at(60.0, 0.0, 0.0, 0.0));
+        assert_eq!((volume, pan), (0.0, 0.0));
+    }
+
+    #[test]
+    fn pans_to_the_listeners_side() {
+        let (_, pan) = place(at(0.0, 0.0, 0.0, 0.0), at(3.0, 0.0, 0.0, 0.0));
+        assert!((pan - 1.0).abs() < 1e-6);
+        let (_, pan) = place(at(0.0, 0.0, 0.0, 0.0), at(-3.0, 0.0, 0.0, 0.0));
+        assert!((pan + 1.0).abs() < 1e-6);
+        // turned a quarter, what was in front or behind is now to the side.
+        let (_, pan) = place(at(0.0, 0.0, 0.0, FRAC_PI_2), at(0.0, 0.0, -3.0, 0.0));
+        assert!((pan - 1.0).abs() < 1e-6);
+        let (_, pan) = place(at(0.0, 0.0, 0.0, FRAC_PI_2), at(0.0, 0.0, 3.0, 0.0));
+        assert!((pan + 1.0).abs() < 1e-6);
+        let (_, pan) = place(at(0.0, 0.0, 0.0, FRAC_PI_2), at(3.0, 0.0, 0.0, 0.0));
+        assert!(pan.abs() < 1e-6);
+    }
+
+    #[test]
+    fn centers_a_source_overhead() {
+        let (_, pan) = place(at(1.0, 0.0, 1.0, 0.3), at(1.0, 5.0, 1.0, 0.0));
+        assert_eq!(pan, 0.0);
+        // partly above, it pans less than one level with the listener.
+        let (_, pan) = place(at(0.0, 0.0, 0.0, 0.0), at(3.0, 3.0, 0.0, 0.0));
+        assert!((pan - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn unplaced_sources_are_heard_as_is() {
+        let listener = at(0.0, 0.0, 0.0, 0.0);
+        assert_eq!(
+            placement(&SpatialSettings::default(), Some(&listener), None),
+            (1.0, 0.0)
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use crate::{
+    codec::{Codec, FrameError, Framing},
+    config::{Config, ConfigError},
+    message::{RequestMessage, ResponseMessage},
+};
+use bytes::Bytes;
+use futures::{future, Sink, SinkExt, StreamExt};
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    convert::TryFrom, fs::File, io, io::BufReader, net::IpAddr, pin::Pin, sync::Arc, time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{lookup_host, TcpStream},
+};
+use tokio_rustls::{
+    rustls::{self, Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore},
+    TlsConnector,
+};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+// a message that fails to decode is an `Ok(Err(_))`, the connection itself is still fine.
+pub type MessageStream =
+    Pin<Box<dyn futures::Stream<Item = io::Result<Result<RequestMessage, FrameError>>> + Send>>;
+pub type MessageSink = Pin<Box<dyn Sink<ResponseMessage, Error = io::Error> + Send>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    // json framed by the 4-byte length prefix of `codec::Framing::Server`.
+    Tcp,
+    // json in websocket text frames.
+    Ws,
+    // websocket over tls.
+    Wss,
+}
+
+impl Scheme {
+    pub fn default_port(self) -> u16 {
+        match self {
+            Scheme::Tcp => 1188,
+            Scheme::Ws => 80,
+            Scheme::Wss => 443,
+        }
+    }
+}
+
+// where the api server is, as parsed from the url.
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    pub scheme: Scheme,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+// an open connection to the api server, whichever transport carries it.
+pub struct Connection {
+    pub read: MessageStream,
+    pub write: MessageSink,
+    // our end of the connection, announced as media address unless public_ip is set.
+    pub local_ip: IpAddr,
+}
+
+// opens connections to the api server; built once from the config so bad tls
+// settings are reported at startup rather than on every reconnect.
+pub struct Connector {
+    endpoint: Endpoint,
+    keepalive: Duration,
+    max_frame_size: usize,
+    tls: Option<(TlsConnector, rustls::ServerName)>,
+}
+
+impl Connector {
+    pub fn new(config: &Config) -> Result<Self, ConfigError> {
+        let endpoint = config.endpoint.clone();
+        let tls = if config.tls.enabled || endpoint.scheme == Scheme::Wss {
+            let server_name = config
+                .tls
+                .server_name
+                .clone()
+                .unwrap_or_else(|| endpoint.host.clone());
+            let server_name = rustls::ServerName::try_from(server_name.as_str())
+                .map_err(|e| ConfigError::Invalid("tls_server_name", e.to_string()))?;
+            Some((
+                TlsConnector::from(Arc::new(client_config(config)?)),
+                server_name,
+            ))
+        } else {
+            None
+        };
+        Ok(Connector {
+            endpoint,
+            keepalive: config.keepalive_timeout,
+            max_frame_size: config.max_frame_size,
+            tls,
+        })
+    }
+
+    pub async fn connect(&self) -> io::Result<Connection> {
+        let stream = connect_tcp(&self.endpoint).await?;
+        stream.set_nodelay(true)?;
+        // let the kernel notice a vanished peer too, even while nothing is being written.
+        let probe = self.keepalive / 3;
+        SockRef::from(&stream).set_tcp_keepalive(
+            &TcpKeepalive::new()
+                .with_time(probe)
+                .with_interval(probe)
+                .with_retries(2),
+        )?;
+        let local_ip = stream.local_addr()?.ip();
+        let stream: Box<dyn AsyncStream> = match &self.tls {
+            Some((connector, server_name)) => {
+                Box::new(connector.connect(server_name.clone(), stream).await?)
+            }
+            None => Box::new(stream),
+        };
+        let (read, write) = match self.endpoint.scheme {
+            Scheme::Tcp => framed(stream, self.max_frame_size),
+            Scheme::Ws | Scheme::Wss => {
+                websocket(stream, &self.endpoint, self.max_frame_size).await?
+            }
+        };
+        Ok(Connection {
+            read,
+            write,
+            local_ip,
+        })
+    }
+}
+
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+fn framed(stream: Box<dyn AsyncStream>, max_frame_size: usize) -> (MessageStream, MessageSink) {
+    let (read, write) = tokio::io::split(stream);
+    let codec = || Codec::<RequestMessage>::new(Framing::Server, max_frame_size);
+    (
+        Box::pin(FramedRead::new(read, codec())),
+        Box::pin(FramedWrite::new(write, codec())),
+    )
+}
+
+// websocket pings are answered by tungstenite, a close frame ends the stream.
+// Messages beyond tungstenite's own limit of 64 MiB end the connection.
+async fn websocket(
+    stream: Box<dyn AsyncStream>,
+    endpoint: &Endpoint,
+    max_frame_size: usize,
+) -> io::Result<(MessageStream, MessageSink)> {
+    let host = if endpoint.host.contains(':') {
+        format!("[{}]", endpoint.host)
+    } else {
+        endpoint.host.clone()
+    };
+    let scheme = match endpoint.scheme {
+        Scheme::Wss => "wss",
+        _ => "ws",
+    };
+    let url = format!("{}://{}:{}{}", scheme, host, endpoint.port, endpoint.path);
+    let (websocket, _) = tokio_tungstenite::client_async(url, stream)
+        .await
+        .map_err(io::Error::other)?;
+    let (write, read) = websocket.split();
+    let decode = move |frame: Bytes| {
+        if frame.len() > max_frame_size {
+            return Err(FrameError::TooLarge {
+                size: frame.len(),
+                max: max_frame_size,
+            });
+        }
+        serde_json::from_slice(&frame).map_err(|error| FrameError::Json { error, frame })
+    };
+    let read = read.filter_map(move |message| {
+        future::ready(match message {
+            Ok(Message::Text(text)) => Some(Ok(decode(Bytes::from(text)))),
+            Ok(Message::Binary(data)) => Some(Ok(decode(Bytes::from(data)))),
+            Ok(_) => None,
+            Err(e) => Some(Err(io::Error::other(e))),
+        })
+    });
+    let write = write
+        .sink_map_err(io::Error::other)
+        .with(move |message: ResponseMessage| {
+            future::ready(match serde_json::to_string(&message) {
+                Ok(text) if text.len() > max_frame_size => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    FrameError::TooLarge {
+                        size: text.len(),
+                        max: max_frame_size,
+                    },
+                )),
+                Ok(text) => Ok(Message::Text(text)),
+                Err(e) => Err(e.into()),
+            })
+        });
+    Ok((Box::pin(read), Box::pin(write)))
+}
+
+async fn connect_tcp(endpoint: &Endpoint) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in lookup_host((endpoint.host.as_str(), endpoint.port)).await? {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", endpoint.host),
+        )
+    }))
+}
+
+// server certificates are checked against `tls_ca` if given, the webpki roots otherwise.
+fn client_config(config: &Config) -> Result<ClientConfig, ConfigError> {
+    let mut roots = RootCertStore::empty();
+    match &config.tls.ca {
+        Some(path) => {
+            for cert in read_certs("tls_ca", path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| ConfigError::Invalid("tls_ca", e.to_string()))?;
+            }
+        }
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    match (&config.tls.cert, &config.tls.key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(read_certs("tls_cert", cert)?, read_key(key)?)
+            .map_err(|e| ConfigError::Invalid("tls_cert", e.to_string())),
+        (None, None) => Ok(builder.with_no_client_auth()),
+        (Some(_), None) => Err(ConfigError::Missing("tls_key")),
+        (None, Some(_)) => Err(ConfigError::Missing("tls_cert")),
+    }
+}
+
+fn read_certs(setting: &'static str, path: &str) -> Result<Vec<Certificate>, ConfigError> {
+    let file = File::open(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+    if certs.is_empty() {
+        return Err(ConfigError::Invalid(
+            setting,
+            format!("no certificates in {}", path),
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// the first pkcs8, rsa or ec key in the file.
+fn read_key(path: &str) -> Result<PrivateKey, ConfigError> {
+    let file = File::open(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| ConfigError::Read(path.to_owned(), e))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => {
+                return Err(ConfigError::Invalid(
+                    "tls_key",
+                    format!("no private key in {}", path),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Args,
+        message::{MessageRequest, MessageResponse},
+    };
+    use clap::Parser;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa};
+    use tokio::net::TcpListener;
+    use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+    // a throwaway CA and a certificate it signed for localhost.
+    fn certificates() -> (String, Vec<Certificate>, PrivateKey) {
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        let server = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let chain = vec![Certificate(server.serialize_der_with_signer(&ca).unwrap())];
+        let key = PrivateKey(server.serialize_private_key_der());
+        (ca.serialize_pem().unwrap(), chain, key)
+    }
+
+    #[tokio::test]
+    async fn connects_over_tls() {
+        let (ca, chain, key) = certificates();
+        let ca_path = std::env::temp_dir().join(format!("gmix-test-ca-{}.pem", std::process::id()));
+        std::fs::write(&ca_path, ca).unwrap();
+
+        let acceptor = TlsAcceptor::from(Arc::new(
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(chain, key)
+                .unwrap(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            let (read, write) = tokio::io::split(stream);
+            let codec = || Codec::<serde_json::Value>::new(Framing::Server, 1024);
+            let mut write = FramedWrite::new(write, codec());
+            let ping = RequestMessage::IncomingServer {
+                node: None,
+                wsid: None,
+                request_id: None,
+                auth: None,
+                message: MessageRequest::ping {},
+                digest: String::new(),
+            };
+            write.send(ping).await.unwrap();
+            FramedRead::new(read, codec()).next().await
+        });
+
+        let args = Args::try_parse_from([
+            "gmix",
+            "--url",
+            &format!("localhost:{}", port),
+            "--tls",
+            "--tls-ca",
+            ca_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        let connector = Connector::new(&Config::load(args).unwrap()).unwrap();
+        let mut connection = connector.connect().await.unwrap();
+        std::fs::remove_file(&ca_path).unwrap();
+
+        match connection.read.next().await {
+            Some(Ok(Ok(RequestMessage::IncomingServer { message, .. }))) => {
+                assert_eq!(message.name(), "ping")
+            }
+            other => panic!("expected a ping, got {:?}", other),
+        }
+        connection
+            .write
+            .send(ResponseMessage::OutgoingServer {
+                node: None,
+                wsid: None,
+                request_id: None,
+                message: MessageResponse::pong {},
+            })
+            .await
+            .unwrap();
+        let pong = server.await.unwrap().unwrap().unwrap().unwrap();
+        assert_eq!(pong["message"]["type"], "pong");
+    }
+}